@@ -0,0 +1,22 @@
+//! `Archive::parse` and extracting every entry it lists
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lzw::archive::Archive;
+use lzw::limits::Limits;
+
+const LIMITS: Limits = Limits {
+    max_entries: None,
+    max_output: Some(1 << 22),
+    max_memory: Some(1 << 28),
+    max_work: Some(1 << 24),
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(archive) = Archive::parse(data) {
+        for entry in archive.entries() {
+            let _ = archive.extract(entry, None, LIMITS);
+        }
+    }
+});
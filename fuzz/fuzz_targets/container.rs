@@ -0,0 +1,27 @@
+//! Everything that reads a compressed file: the header, members, block
+//! index and frames, and the decoders behind them
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lzw::container::*;
+use lzw::limits::Limits;
+
+const LIMITS: Limits = Limits {
+    max_entries: None,
+    max_output: Some(1 << 22),
+    max_memory: Some(1 << 28),
+    max_work: Some(1 << 24),
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Header::parse(data);
+    let _ = members(data);
+    let _ = inspect(data, false);
+
+    for legacy_huffman in [false, true] {
+        let _ = decompress_parallel(data, legacy_huffman, None, 2, LIMITS);
+    }
+    let _ = decompress_range(data, false, None, 1000, 100, LIMITS);
+    let _ = code_widths(data, false, None, LIMITS);
+});
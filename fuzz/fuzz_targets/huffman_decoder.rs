@@ -0,0 +1,19 @@
+//! `HuffmanDecoder` over arbitrary frequency tables and codewords
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lzw::huffman::HuffmanDecoder;
+use lzw::limits::Limits;
+
+const LIMITS: Limits = Limits {
+    max_entries: None,
+    max_output: Some(1 << 22),
+    max_memory: Some(1 << 28),
+    max_work: Some(1 << 24),
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = HuffmanDecoder::summarize(data);
+    let _ = HuffmanDecoder::with_limits(LIMITS).decode(data);
+});
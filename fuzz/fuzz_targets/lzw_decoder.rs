@@ -0,0 +1,36 @@
+//! `LzwDecoder` over arbitrary codewords, under a configuration picked by
+//! the first byte
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lzw::limits::Limits;
+use lzw::lzw::*;
+use lzw::u8_to_usize;
+
+const LIMITS: Limits = Limits {
+    max_entries: None,
+    max_output: Some(1 << 22),
+    max_memory: Some(1 << 28),
+    max_work: Some(1 << 24),
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&settings, stream)) = data.split_first() else {
+        return;
+    };
+    let settings = usize::from(settings);
+
+    let mode = [Mode::Bytes, Mode::Text, Mode::Legacy][settings % 3];
+    let growth = [Growth::Classic, Growth::Lzmw, Growth::Lzap][settings / 3 % 3];
+    let full_policy = [
+        FullPolicy::Freeze,
+        FullPolicy::Clear,
+        FullPolicy::Lru,
+        FullPolicy::AdaptiveClear { threshold: 0 },
+    ][settings / 9 % 4];
+    let max_word_size = 9 + settings / 36 % 8;
+
+    let config = LzwConfig { max_word_size, full_policy, growth, limits: LIMITS, ..LzwConfig::new(mode) };
+    let _ = LzwDecoder::new(config).decode(u8_to_usize(stream.to_vec()));
+});
@@ -0,0 +1,10 @@
+//! `PresetDictionary::parse`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lzw::preset::PresetDictionary;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = PresetDictionary::parse(data);
+});
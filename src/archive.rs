@@ -0,0 +1,465 @@
+use std::sync::Arc;
+
+use crate::checksum::crc32;
+use crate::container::*;
+use crate::error::Error;
+use crate::huffman::HuffmanStage;
+use crate::limits::{Limit, Limits};
+use crate::lzw::LzwConfig;
+use crate::preset::PresetDictionary;
+
+/// First bytes of every archive
+const MAGIC: [u8; 4] = *b"LZWA";
+
+/// Last bytes of every archive, after the trailer
+const END_MAGIC: [u8; 4] = *b"LZWX";
+
+const VERSION: u8 = 1;
+
+/// Magic and version
+const PREAMBLE_SIZE: usize = 5;
+
+/// Index offset (u64 LE), index checksum (u32 LE), end magic
+const TRAILER_SIZE: usize = 16;
+
+
+/// How an entry's contents were stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pipeline {
+    /// As they are, for contents that don't compress
+    Stored,
+    /// A container stream from `compress`
+    Lzw,
+    /// A container stream from `compress` with the Huffman stage on
+    LzwHuffman,
+}
+
+impl Pipeline {
+    pub fn id(self) -> u8 {
+        match self {
+            Pipeline::Stored => 0,
+            Pipeline::Lzw => 1,
+            Pipeline::LzwHuffman => 2,
+        }
+    }
+
+    pub fn from_id(id : u8) -> Option<Self> {
+        match id {
+            0 => Some(Pipeline::Stored),
+            1 => Some(Pipeline::Lzw),
+            2 => Some(Pipeline::LzwHuffman),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pipeline::Stored => "stored",
+            Pipeline::Lzw => "lzw",
+            Pipeline::LzwHuffman => "lzw+huffman",
+        }
+    }
+}
+
+
+/// One file in an archive, as recorded in the central index.
+///
+/// Index layout per entry: path length (u16 LE), path (UTF-8, `/` separated),
+/// pipeline (1), permissions (u32 LE), mtime (i64 LE, seconds since the Unix
+/// epoch), size (u64 LE), offset (u64 LE), stored size (u64 LE), CRC-32 of
+/// the contents (u32 LE)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative path, with `/` between components
+    pub path: String,
+    pub pipeline: Pipeline,
+    /// Unix permission bits
+    pub permissions: u32,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: i64,
+    /// Length in bytes of the contents
+    pub size: u64,
+    /// Where the stored data starts in the archive
+    pub offset: u64,
+    /// Length in bytes of the stored data
+    pub stored_size: u64,
+    /// `crc32` of the contents
+    pub checksum: u32,
+}
+
+impl Entry {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend((self.path.len() as u16).to_le_bytes());
+        bytes.extend(self.path.as_bytes());
+        bytes.push(self.pipeline.id());
+        bytes.extend(self.permissions.to_le_bytes());
+        bytes.extend(self.mtime.to_le_bytes());
+        bytes.extend(self.size.to_le_bytes());
+        bytes.extend(self.offset.to_le_bytes());
+        bytes.extend(self.stored_size.to_le_bytes());
+        bytes.extend(self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the entry at the start of `input`, returning it and what follows
+    fn parse(input : &[u8]) -> Result<(Entry, &[u8]), Error> {
+        let mut reader = Reader(input);
+
+        let path_length = u16::from_le_bytes(reader.take()?) as usize;
+        let path = std::str::from_utf8(reader.slice(path_length)?)
+            .map_err(|_| Error::BadArchive("path is not UTF-8"))?
+            .to_string();
+        check_path(&path)?;
+
+        let [pipeline] = reader.take()?;
+        let entry = Entry {
+            path,
+            pipeline : Pipeline::from_id(pipeline).ok_or(Error::BadArchive("unknown pipeline"))?,
+            permissions : u32::from_le_bytes(reader.take()?),
+            mtime : i64::from_le_bytes(reader.take()?),
+            size : u64::from_le_bytes(reader.take()?),
+            offset : u64::from_le_bytes(reader.take()?),
+            stored_size : u64::from_le_bytes(reader.take()?),
+            checksum : u32::from_le_bytes(reader.take()?),
+        };
+        Ok((entry, reader.0))
+    }
+}
+
+/// Cursor over the index that fails instead of running off the end
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn slice(&mut self, length : usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < length {
+            return Err(Error::BadArchive("truncated index"));
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+}
+
+/// Paths must stay inside the directory the archive is extracted to, on
+/// Windows too, where `\` separates components and `:` starts a drive or
+/// stream name. No file name holds NUL.
+fn check_path(path : &str) -> Result<(), Error> {
+    let escapes = path.is_empty()
+        || path.starts_with('/')
+        || path.split('/').any(|component| component.is_empty() || component == "." || component == "..");
+
+    if escapes || path.len() > usize::from(u16::MAX) {
+        Err(Error::BadArchive("path is empty, absolute or leaves the archive"))
+    } else if path.contains(['\\', ':', '\0']) {
+        Err(Error::BadArchive("path holds a backslash, colon or NUL"))
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Builds an archive in memory, compressing each entry as it is added.
+///
+/// Layout: magic (4), version (1), the entries' stored data back to back,
+/// the central index (entry count, u32 LE, then each `Entry`), and a trailer
+/// of the index offset (u64 LE), the index's CRC-32 (u32 LE) and an end magic (4)
+pub struct ArchiveWriter {
+    config: LzwConfig,
+    huffman: HuffmanStage,
+    output: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl ArchiveWriter {
+    pub fn new(config : LzwConfig, huffman : HuffmanStage) -> Self {
+        let mut output = MAGIC.to_vec();
+        output.push(VERSION);
+        ArchiveWriter { config, huffman, output, entries : vec![] }
+    }
+
+    /// Adds a file, compressed with the writer's settings unless that
+    /// doesn't make it smaller or its contents can't be encoded in text mode
+    pub fn add(&mut self, path : &str, permissions : u32, mtime : i64, contents : &[u8]) -> Result<&Entry, Error> {
+        check_path(path)?;
+        if self.entries.iter().any(|entry| entry.path == path) {
+            return Err(Error::BadArchive("path is already in the archive"));
+        }
+
+        let (pipeline, data) = match compress(contents, &self.config, self.huffman) {
+            Ok(compressed) if compressed.len() < contents.len() => {
+                let huffman = Header::parse(&compressed)?.is_some_and(|header| header.huffman);
+                let pipeline = if huffman { Pipeline::LzwHuffman } else { Pipeline::Lzw };
+                (pipeline, compressed)
+            },
+            Ok(_) | Err(Error::InvalidUtf8) => (Pipeline::Stored, contents.to_vec()),
+            Err(e) => return Err(e),
+        };
+
+        self.entries.push(Entry {
+            path : path.to_string(),
+            pipeline,
+            permissions,
+            mtime,
+            size : contents.len() as u64,
+            offset : self.output.len() as u64,
+            stored_size : data.len() as u64,
+            checksum : crc32(contents),
+        });
+        self.output.extend(data);
+        Ok(self.entries.last().unwrap())
+    }
+
+    /// Entries added so far
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Appends the index and trailer
+    pub fn finish(mut self) -> Vec<u8> {
+        let index_offset = self.output.len() as u64;
+
+        let mut index = (self.entries.len() as u32).to_le_bytes().to_vec();
+        for entry in self.entries.iter() {
+            index.extend(entry.to_bytes());
+        }
+
+        self.output.extend(&index);
+        self.output.extend(index_offset.to_le_bytes());
+        self.output.extend(crc32(&index).to_le_bytes());
+        self.output.extend(END_MAGIC);
+        self.output
+    }
+}
+
+
+/// An archive read back from its bytes. Only the index is parsed up front,
+/// entries are decoded when extracted.
+pub struct Archive<'a> {
+    data: &'a [u8],
+    entries: Vec<Entry>,
+}
+
+impl<'a> Archive<'a> {
+    pub fn parse(data : &'a [u8]) -> Result<Self, Error> {
+        if !data.starts_with(&MAGIC) {
+            return Err(Error::BadArchive("not an archive"));
+        }
+        if data.len() < PREAMBLE_SIZE + TRAILER_SIZE || !data.ends_with(&END_MAGIC) {
+            return Err(Error::BadArchive("truncated"));
+        }
+        if data[4] != VERSION {
+            return Err(Error::UnsupportedVersion(data[4]));
+        }
+
+        let trailer = &data[data.len() - TRAILER_SIZE..];
+        let index_end = data.len() - TRAILER_SIZE;
+        let index_offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let index_checksum = u32::from_le_bytes(trailer[8..12].try_into().unwrap());
+
+        if index_offset < PREAMBLE_SIZE as u64 || index_offset > index_end as u64 {
+            return Err(Error::BadArchive("index offset out of range"));
+        }
+        let index = &data[index_offset as usize..index_end];
+        let found = crc32(index);
+        if found != index_checksum {
+            return Err(Error::ChecksumMismatch { expected : index_checksum, found });
+        }
+
+        let mut reader = Reader(index);
+        let count = u32::from_le_bytes(reader.take()?);
+        let mut rest = reader.0;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let (entry, after) = Entry::parse(rest)?;
+            if entry.offset < PREAMBLE_SIZE as u64
+                || entry.offset.checked_add(entry.stored_size).is_none_or(|end| end > index_offset)
+            {
+                return Err(Error::BadArchive("entry data out of range"));
+            }
+            entries.push(entry);
+            rest = after;
+        }
+        if !rest.is_empty() {
+            return Err(Error::BadArchive("trailing bytes in index"));
+        }
+
+        Ok(Archive { data, entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Decodes an entry's contents within `limits` and checks them against its
+    /// size and checksum
+    pub fn extract(
+        &self,
+        entry : &Entry,
+        preset : Option<&Arc<PresetDictionary>>,
+        limits : Limits,
+    ) -> Result<Vec<u8>, Error> {
+        limits.check_output(entry.size)?;
+        let start = entry.offset as usize;
+        let stored = self
+            .data
+            .get(start..start + entry.stored_size as usize)
+            .ok_or(Error::BadArchive("entry data out of range"))?;
+
+        let contents = match entry.pipeline {
+            Pipeline::Stored => stored.to_vec(),
+            Pipeline::Lzw | Pipeline::LzwHuffman => {
+                if Header::parse(stored)?.is_none() {
+                    return Err(Error::BadArchive("compressed entry has no header"));
+                }
+                // Stop decoding as soon as the contents are past the entry's size
+                let limits = Limits { max_output : Some(entry.size), ..limits };
+                match decompress_parallel(stored, false, preset, 1, limits) {
+                    Err(Error::LimitExceeded { limit : Limit::Output, .. }) => {
+                        return Err(Error::BadArchive("entry decoded to the wrong size"));
+                    },
+                    result => result?,
+                }
+            },
+        };
+
+        if contents.len() as u64 != entry.size {
+            return Err(Error::BadArchive("entry decoded to the wrong size"));
+        }
+        let found = crc32(&contents);
+        if found != entry.checksum {
+            return Err(Error::ChecksumMismatch { expected : entry.checksum, found });
+        }
+        Ok(contents)
+    }
+}
+
+
+#[cfg(test)]
+mod archive_test {
+    use crate::archive::*;
+    use crate::lzw::Mode;
+
+    fn sample() -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Text), HuffmanStage::Off);
+        writer.add("folktale.txt", 0o644, 1_700_000_000, include_str!("../folktale.txt").as_bytes()).unwrap();
+        writer.add("bin/tiny", 0o755, -5, &[0xFF, 0x00, 0xC3]).unwrap();
+        writer.add("empty", 0o600, 0, b"").unwrap();
+        writer.finish()
+    }
+
+    #[test]
+    fn round_trip_keeps_metadata() {
+        let bytes = sample();
+        let archive = Archive::parse(&bytes).unwrap();
+        let entries = archive.entries();
+
+        assert_eq!(entries.iter().map(|e| e.path.as_str()).collect::<Vec<&str>>(), ["folktale.txt", "bin/tiny", "empty"]);
+        assert_eq!(entries.iter().map(|e| e.pipeline).collect::<Vec<Pipeline>>(), [Pipeline::Lzw, Pipeline::Stored, Pipeline::Stored]);
+        assert_eq!((entries[1].permissions, entries[1].mtime, entries[1].size), (0o755, -5, 3));
+
+        assert_eq!(archive.extract(&entries[0], None, Limits::none()).unwrap(), include_str!("../folktale.txt").as_bytes());
+        assert_eq!(archive.extract(&entries[1], None, Limits::none()).unwrap(), [0xFF, 0x00, 0xC3]);
+        assert!(archive.extract(&entries[2], None, Limits::none()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn huffman_pipeline_is_recorded() {
+        let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Bytes), HuffmanStage::On);
+        let entry = writer.add("folktale.txt", 0o644, 0, include_str!("../folktale.txt").as_bytes()).unwrap();
+        assert_eq!(entry.pipeline, Pipeline::LzwHuffman);
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_archive() {
+        let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Bytes), HuffmanStage::Off);
+        let windows = ["..\\..\\evil", "a\\b", "C:evil", "C:\\Windows", "\\\\server\\share", "file:stream"];
+        for path in ["", "/etc/passwd", "../up", "a/../../b", "a//b", "./a", "a\0b"].into_iter().chain(windows) {
+            assert!(matches!(writer.add(path, 0, 0, b"x"), Err(Error::BadArchive(_))), "{path:?}");
+        }
+        writer.add("a", 0, 0, b"x").unwrap();
+        assert!(writer.add("a", 0, 0, b"y").is_err());
+    }
+
+    #[test]
+    fn index_paths_are_checked_when_read() {
+        for character in [b'\\', b':', 0] {
+            let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Bytes), HuffmanStage::Off);
+            writer.add("C_evil", 0, 0, b"x").unwrap();
+            let mut bytes = writer.finish();
+
+            // Put the character in the path, and the index checksum right again
+            let at = bytes.windows(6).rposition(|window| window == b"C_evil").unwrap();
+            bytes[at + 1] = character;
+            let trailer = bytes.len() - TRAILER_SIZE;
+            let index_offset = u64::from_le_bytes(bytes[trailer..trailer + 8].try_into().unwrap()) as usize;
+            let checksum = crc32(&bytes[index_offset..trailer]);
+            bytes[trailer + 8..trailer + 12].copy_from_slice(&checksum.to_le_bytes());
+
+            assert!(matches!(Archive::parse(&bytes), Err(Error::BadArchive(_))), "{character}");
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let bytes = sample();
+
+        // Flip a bit in the stored data of the last, uncompressed entries
+        let mut corrupt = bytes.clone();
+        let archive = Archive::parse(&bytes).unwrap();
+        corrupt[archive.entries()[1].offset as usize] ^= 1;
+        let archive = Archive::parse(&corrupt).unwrap();
+        assert!(matches!(archive.extract(&archive.entries()[1], None, Limits::none()), Err(Error::ChecksumMismatch { .. })));
+
+        // Anywhere in the index or trailer
+        for position in bytes.len() - TRAILER_SIZE - 10..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[position] ^= 0x10;
+            assert!(Archive::parse(&corrupt).is_err(), "byte {position}");
+        }
+
+        for length in 0..bytes.len() {
+            assert!(Archive::parse(&bytes[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn extract_keeps_to_the_limits() {
+        let bytes = sample();
+        let archive = Archive::parse(&bytes).unwrap();
+        let (folktale, tiny) = (&archive.entries()[0], &archive.entries()[1]);
+
+        let limits = Limits { max_entries : Some(300), ..Limits::none() };
+        assert!(matches!(archive.extract(folktale, None, limits), Err(Error::LimitExceeded { limit : Limit::Entries, .. })));
+        let limits = Limits { max_output : Some(folktale.size - 1), ..Limits::none() };
+        assert!(matches!(archive.extract(folktale, None, limits), Err(Error::LimitExceeded { limit : Limit::Output, .. })));
+
+        // Stored entries too, though nothing is decoded
+        let limits = Limits { max_output : Some(2), ..Limits::none() };
+        assert!(matches!(archive.extract(tiny, None, limits), Err(Error::LimitExceeded { limit : Limit::Output, .. })));
+        let limits = Limits { max_output : Some(3), ..Limits::none() };
+        assert_eq!(archive.extract(tiny, None, limits).unwrap(), [0xFF, 0x00, 0xC3]);
+    }
+
+    #[test]
+    fn every_format_version_still_extracts() {
+        // Written when entries held version 1 members, and with version 3 ones
+        for file in [&include_bytes!("../golden/v1.lzwa")[..], include_bytes!("../golden/v3.lzwa")] {
+            let archive = Archive::parse(file).unwrap();
+            let contents = archive
+                .entries()
+                .iter()
+                .map(|entry| (entry.path.as_str(), archive.extract(entry, None, Limits::none()).unwrap()))
+                .collect::<Vec<(&str, Vec<u8>)>>();
+
+            assert_eq!(contents, [
+                ("arch/input.txt", include_bytes!("../golden/input.txt").to_vec()),
+                ("arch/legacy.txt", include_bytes!("../golden/legacy.txt").to_vec()),
+            ]);
+        }
+    }
+}
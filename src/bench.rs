@@ -0,0 +1,309 @@
+//! `lzw bench`: runs every codec over a directory of files and reports how
+//! small, how fast and how memory hungry each one is
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use lzw::container::*;
+use lzw::dump::json_string;
+use lzw::huffman::*;
+use lzw::lzw::*;
+
+/// The system allocator, keeping count of the bytes in use and the most
+/// there have been since `measure` last started
+struct CountingAllocator;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated(size : usize) {
+    let in_use = IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(in_use, Ordering::Relaxed);
+}
+
+fn freed(size : usize) {
+    IN_USE.fetch_sub(size, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+        if !pointer.is_null() {
+            allocated(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn alloc_zeroed(&self, layout : Layout) -> *mut u8 {
+        let pointer = System.alloc_zeroed(layout);
+        if !pointer.is_null() {
+            allocated(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer : *mut u8, layout : Layout) {
+        System.dealloc(pointer, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, pointer : *mut u8, layout : Layout, new_size : usize) -> *mut u8 {
+        let new_pointer = System.realloc(pointer, layout, new_size);
+        if !new_pointer.is_null() {
+            if new_size > layout.size() {
+                allocated(new_size - layout.size());
+            } else {
+                freed(layout.size() - new_size);
+            }
+        }
+        new_pointer
+    }
+}
+
+/// Runs `f`, returning what it did, how long it took, and the most memory
+/// it had allocated at once
+fn measure<T>(f : impl FnOnce() -> T) -> (T, Duration, usize) {
+    let baseline = IN_USE.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+
+    (result, elapsed, PEAK.load(Ordering::Relaxed).saturating_sub(baseline))
+}
+
+
+/// What a codec does to the input
+enum Pipeline {
+    Lzw(LzwConfig, HuffmanStage),
+    /// The Huffman codec by itself, without a container
+    Huffman,
+}
+
+pub struct Codec {
+    /// The flags `lzw` takes to compress the same way
+    pub name : String,
+    pipeline : Pipeline,
+}
+
+impl Codec {
+    fn compress(&self, input : &[u8]) -> Result<Vec<u8>, String> {
+        match &self.pipeline {
+            Pipeline::Lzw(config, huffman) => compress(input, config, *huffman).map_err(|e| e.to_string()),
+            Pipeline::Huffman => Ok(HuffmanEncoder::new().encode(input)),
+        }
+    }
+
+    /// `original_size` is needed for the Huffman codec, whose padding bits
+    /// decode to extra bytes
+    fn decompress(&self, input : &[u8], original_size : usize) -> Result<Vec<u8>, String> {
+        match &self.pipeline {
+            Pipeline::Lzw(..) => decompress(input, false, None).map_err(|e| e.to_string()),
+            Pipeline::Huffman => {
+                let mut output = HuffmanDecoder::new().decode(input).map_err(|e| e.to_string())?;
+                output.truncate(original_size);
+                Ok(output)
+            },
+        }
+    }
+}
+
+/// Every level, then the variants of the default level one setting at a
+/// time, then the Huffman codec alone
+pub fn codecs() -> Vec<Codec> {
+    let lzw = |name : String, config : LzwConfig, huffman : HuffmanStage| Codec {
+        name,
+        pipeline : Pipeline::Lzw(config, huffman),
+    };
+    let default = LzwConfig::level(Mode::Bytes, 6);
+
+    let mut codecs = (1..=9u8)
+        .map(|level| {
+            lzw(format!("lzw -{level}"), LzwConfig::level(Mode::Bytes, level), HuffmanStage::level(level))
+        })
+        .collect::<Vec<Codec>>();
+
+    codecs.push(lzw("lzw -6 --hm".to_string(), default.clone(), HuffmanStage::On));
+
+    for (name, growth) in [("lzmw", Growth::Lzmw), ("lzap", Growth::Lzap)] {
+        codecs.push(lzw(format!("lzw -6 --growth {name}"), LzwConfig { growth, ..default.clone() }, HuffmanStage::Off));
+    }
+    for (name, full_policy) in [("freeze", FullPolicy::Freeze), ("clear", FullPolicy::Clear), ("lru", FullPolicy::Lru)] {
+        codecs.push(lzw(format!("lzw -6 --policy {name}"), LzwConfig { full_policy, ..default.clone() }, HuffmanStage::Off));
+    }
+    codecs.push(lzw(
+        "lzw -6 --parsing flexible".to_string(),
+        LzwConfig { parsing : Parsing::Flexible, ..default.clone() },
+        HuffmanStage::Off,
+    ));
+
+    codecs.push(Codec { name : "huffman".to_string(), pipeline : Pipeline::Huffman });
+    codecs
+}
+
+
+/// Totals for one codec over the corpus
+#[derive(Debug, Clone, Default)]
+pub struct Row {
+    pub codec : String,
+    pub files : usize,
+    pub original : usize,
+    pub compressed : usize,
+    pub compress_time : Duration,
+    pub decompress_time : Duration,
+    /// Most memory in use at once while compressing or decompressing any one file
+    pub peak_memory : usize,
+    /// Files that failed to compress, or didn't come back the same
+    pub failures : Vec<String>,
+}
+
+impl Row {
+    pub fn ratio(&self) -> f64 {
+        self.original as f64 / self.compressed.max(1) as f64
+    }
+
+    pub fn compress_speed(&self) -> f64 {
+        megabytes_per_second(self.original, self.compress_time)
+    }
+
+    pub fn decompress_speed(&self) -> f64 {
+        megabytes_per_second(self.original, self.decompress_time)
+    }
+}
+
+fn megabytes_per_second(bytes : usize, time : Duration) -> f64 {
+    bytes as f64 / 1e6 / time.as_secs_f64().max(1e-9)
+}
+
+/// Runs `codec` over every file, checking each comes back the same
+pub fn run(codec : &Codec, files : &[(PathBuf, Vec<u8>)]) -> Row {
+    let mut row = Row { codec : codec.name.clone(), files : files.len(), ..Row::default() };
+
+    for (path, input) in files {
+        let (compressed, compress_time, compress_memory) = measure(|| codec.compress(input));
+        let compressed = match compressed {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                row.failures.push(format!("{}: {e}", path.display()));
+                continue;
+            },
+        };
+
+        let (decompressed, decompress_time, decompress_memory) =
+            measure(|| codec.decompress(&compressed, input.len()));
+        match decompressed {
+            Ok(decompressed) if decompressed == *input => {},
+            Ok(_) => row.failures.push(format!("{}: did not round trip", path.display())),
+            Err(e) => row.failures.push(format!("{}: {e}", path.display())),
+        }
+
+        row.original += input.len();
+        row.compressed += compressed.len();
+        row.compress_time += compress_time;
+        row.decompress_time += decompress_time;
+        row.peak_memory = row.peak_memory.max(compress_memory).max(decompress_memory);
+    }
+    row
+}
+
+
+pub fn table_heading() -> String {
+    format!(
+        "{:<28} {:>12} {:>7} {:>14} {:>16} {:>12}\n",
+        "codec", "compressed", "ratio", "compress MB/s", "decompress MB/s", "peak KiB",
+    )
+}
+
+pub fn table_row(row : &Row) -> String {
+    format!(
+        "{:<28} {:>12} {:>7.3} {:>14.2} {:>16.2} {:>12}\n",
+        row.codec,
+        row.compressed,
+        row.ratio(),
+        row.compress_speed(),
+        row.decompress_speed(),
+        row.peak_memory.div_ceil(1024),
+    )
+}
+
+pub fn csv(rows : &[Row]) -> String {
+    let mut csv = "codec,files,original_bytes,compressed_bytes,ratio,compress_mb_s,decompress_mb_s,peak_memory_bytes,failures\n".to_string();
+    for row in rows {
+        csv += &format!(
+            "\"{}\",{},{},{},{:.4},{:.3},{:.3},{},{}\n",
+            row.codec.replace('"', "\"\""),
+            row.files,
+            row.original,
+            row.compressed,
+            row.ratio(),
+            row.compress_speed(),
+            row.decompress_speed(),
+            row.peak_memory,
+            row.failures.len(),
+        );
+    }
+    csv
+}
+
+pub fn json(directory : &Path, rows : &[Row]) -> String {
+    let codecs = rows
+        .iter()
+        .map(|row| {
+            format!(
+                "    {{\"codec\": {}, \"files\": {}, \"original_bytes\": {}, \"compressed_bytes\": {}, \
+                \"ratio\": {:.4}, \"compress_mb_s\": {:.3}, \"decompress_mb_s\": {:.3}, \
+                \"peak_memory_bytes\": {}, \"failures\": [{}]}}",
+                json_string(&row.codec),
+                row.files,
+                row.original,
+                row.compressed,
+                row.ratio(),
+                row.compress_speed(),
+                row.decompress_speed(),
+                row.peak_memory,
+                row.failures.iter().map(|failure| json_string(failure)).collect::<Vec<String>>().join(", "),
+            )
+        })
+        .collect::<Vec<String>>();
+
+    format!(
+        "{{\n  \"directory\": {},\n  \"codecs\": [\n{}\n  ]\n}}\n",
+        json_string(&directory.display().to_string()),
+        codecs.join(",\n"),
+    )
+}
+
+
+#[cfg(test)]
+mod bench_test {
+    use crate::bench::*;
+
+    #[test]
+    fn every_codec_round_trips() {
+        let files = vec![
+            (PathBuf::from("folktale.txt"), include_str!("../folktale.txt").as_bytes().to_vec()),
+            (PathBuf::from("empty"), vec![]),
+        ];
+
+        for codec in codecs() {
+            let row = run(&codec, &files);
+            assert!(row.failures.is_empty(), "{}: {:?}", codec.name, row.failures);
+            assert_eq!(row.original, files[0].1.len());
+            assert!(row.ratio() > 1.0 && row.peak_memory > 0, "{}", codec.name);
+        }
+    }
+
+    #[test]
+    fn reports_parse() {
+        let rows = [Row { codec : "lzw \"-6\"".to_string(), files : 1, original : 10, compressed : 5, ..Row::default() }];
+
+        assert_eq!(csv(&rows).lines().nth(1).unwrap().split(',').next(), Some("\"lzw \"\"-6\"\"\""));
+        assert!(json(Path::new("corpus"), &rows).contains("\"codec\": \"lzw \\\"-6\\\"\", \"files\": 1"));
+    }
+}
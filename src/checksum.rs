@@ -0,0 +1,64 @@
+/// CRC-32 as used by zlib and gzip (reflected, polynomial 0xEDB88320)
+pub fn crc32(data : &[u8]) -> u32 {
+    Crc32::new().update(data).finish()
+}
+
+/// Running CRC-32 for data that arrives in pieces
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state : u32::MAX }
+    }
+
+    pub fn update(mut self, data : &[u8]) -> Self {
+        for byte in data {
+            self.state = TABLE[((self.state ^ u32::from(*byte)) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+        self
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+
+#[cfg(test)]
+mod checksum_test {
+    use crate::checksum::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(Crc32::new().update(b"1234").update(b"56789").finish(), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,1542 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::checksum::crc32;
+use crate::dump::DictionaryDump;
+use crate::error::Error;
+use crate::huffman::*;
+use crate::limits::{Limit, Limits};
+use crate::lzw::*;
+use crate::preset::PresetDictionary;
+use crate::{u8_to_usize, IntType, INTTYPE_BITS};
+
+/// First bytes of every file with a header. Files without it are legacy output.
+const MAGIC: [u8; 4] = *b"\x89LZW";
+
+/// Version written. Version 1 headers, without the checksum, and version 2
+/// headers, without the payload size, are still read.
+///
+/// Compatibility policy: every file any version wrote keeps decoding to what
+/// was compressed into it, headerless legacy files included up to their
+/// padding, and `LzwDecoder` and `HuffmanDecoder` keep reading the streams
+/// in them. A change to the layout bumps the version, which is never
+/// reused. A new way of coding gets a header field or flag whose old value
+/// means the old way, as growth and the preset dictionary did, so the
+/// bitstream itself needn't be versioned. `golden/` holds files from every
+/// version for the tests to decode.
+const VERSION: u8 = 3;
+
+/// Last bytes of every member from version 3 on, just past the payload
+const END_MARKER: [u8; 4] = *b"\x89END";
+
+/// Payload was passed through the Huffman codec after LZW
+const FLAG_HUFFMAN: u8 = 0b0000_0001;
+
+/// Dictionary was seeded from the preset dictionary named in the header
+const FLAG_PRESET: u8 = 0b0000_0010;
+
+/// Payload is a series of independently compressed blocks, see `Frame`
+const FLAG_BLOCKS: u8 = 0b0000_0100;
+
+/// Blocks are preceded by a `BlockIndex`. Only set along with `FLAG_BLOCKS`.
+const FLAG_INDEX: u8 = 0b0000_1000;
+
+
+/// Describes how the payload following it was produced.
+///
+/// Layout: magic (4), version (1), mode (1), flags (1), max word size (1),
+/// full policy (1), policy parameter (1), growth (1), preset dictionary ID
+/// (u32 LE, zero without `FLAG_PRESET`), original size (u64 LE), from
+/// version 2 on the CRC-32 of the original (u32 LE), and from version 3 on
+/// the payload size (u64 LE)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub mode: Mode,
+    /// Whether the payload, or in block streams any block, went through Huffman
+    pub huffman: bool,
+    /// Whether the payload is made of blocks from `compress_blocks`
+    pub blocks: bool,
+    /// Whether the blocks are preceded by an index of where each one starts
+    pub indexed: bool,
+    /// Widest codeword the LZW stream may use
+    pub max_word_size: u8,
+    /// What the encoder did with a full dictionary
+    pub full_policy: FullPolicy,
+    /// Which entries the dictionary gained from each match
+    pub growth: Growth,
+    /// `PresetDictionary::id` of the dictionary the stream was seeded with
+    pub dictionary_id: Option<u32>,
+    /// Length in bytes of the uncompressed input
+    pub original_size: u64,
+    /// `crc32` of the uncompressed input, `None` in version 1 headers
+    pub checksum: Option<u32>,
+    /// Bytes between the header and the end marker, `None` before version 3,
+    /// where the payload runs to the end of the file. Ignored without a checksum.
+    pub payload_size: Option<u64>,
+}
+
+impl Header {
+    /// Header for `input` compressed with `config` to `payload_size` bytes
+    fn new(input : &[u8], config : &LzwConfig, huffman : bool, blocks : bool, payload_size : usize) -> Self {
+        Header {
+            mode : config.mode,
+            huffman,
+            blocks,
+            indexed : blocks,
+            max_word_size : config.max_word_size as u8,
+            full_policy : config.full_policy,
+            growth : config.growth,
+            dictionary_id : config.preset.as_ref().map(|preset| preset.id()),
+            original_size : input.len() as u64,
+            checksum : Some(crc32(input)),
+            payload_size : Some(payload_size as u64),
+        }
+    }
+
+    /// Size of a version 3 header
+    pub const SIZE: usize = 35;
+
+    /// Size of a version 2 header
+    pub const SIZE_V2: usize = 27;
+
+    /// Size of a version 1 header
+    pub const SIZE_V1: usize = 23;
+
+    /// Version the header is written as: 1 if it has no checksum, 2 if it
+    /// has no payload size
+    pub fn version(&self) -> u8 {
+        match (self.checksum, self.payload_size) {
+            (None, _) => 1,
+            (Some(_), None) => 2,
+            (Some(_), Some(_)) => VERSION,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self.version() {
+            1 => Header::SIZE_V1,
+            2 => Header::SIZE_V2,
+            _ => Header::SIZE,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.huffman {
+            flags |= FLAG_HUFFMAN;
+        }
+        if self.dictionary_id.is_some() {
+            flags |= FLAG_PRESET;
+        }
+        if self.blocks {
+            flags |= FLAG_BLOCKS;
+        }
+        if self.indexed {
+            flags |= FLAG_INDEX;
+        }
+
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend(MAGIC);
+        bytes.push(self.version());
+        bytes.push(self.mode.id());
+        bytes.push(flags);
+        bytes.push(self.max_word_size);
+        bytes.push(self.full_policy.id());
+        bytes.push(self.full_policy.parameter());
+        bytes.push(self.growth.id());
+        bytes.extend(self.dictionary_id.unwrap_or(0).to_le_bytes());
+        bytes.extend(self.original_size.to_le_bytes());
+        if let Some(checksum) = self.checksum {
+            bytes.extend(checksum.to_le_bytes());
+            if let Some(payload_size) = self.payload_size {
+                bytes.extend(payload_size.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// What follows the header at the start of `input`, up to the end marker
+    /// of a member that has one
+    fn payload<'a>(&self, input : &'a [u8]) -> Result<&'a [u8], Error> {
+        let rest = input.get(self.size()..).ok_or(Error::BadHeader("truncated"))?;
+        match self.payload_size {
+            Some(size) => usize::try_from(size)
+                .ok()
+                .and_then(|size| rest.get(..size))
+                .ok_or(Error::Corrupt("member cut short")),
+            None => Ok(rest),
+        }
+    }
+
+    /// Reads the header at the start of `input`.
+    ///
+    /// Returns `None` when the magic is missing, meaning the file predates the header.
+    pub fn parse(input : &[u8]) -> Result<Option<Header>, Error> {
+        if !input.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+        if input.len() < 5 {
+            return Err(Error::BadHeader("truncated"));
+        }
+
+        let version = input[4];
+        let size = match version {
+            1 => Header::SIZE_V1,
+            2 => Header::SIZE_V2,
+            VERSION => Header::SIZE,
+            _ => return Err(Error::UnsupportedVersion(version)),
+        };
+        if input.len() < size {
+            return Err(Error::BadHeader("truncated"));
+        }
+
+        let mode = Mode::from_id(input[5]).ok_or(Error::BadHeader("unknown mode"))?;
+        let flags = input[6];
+        if flags & !(FLAG_HUFFMAN | FLAG_PRESET | FLAG_BLOCKS | FLAG_INDEX) != 0 {
+            return Err(Error::BadHeader("unknown flags"));
+        }
+        if flags & FLAG_INDEX != 0 && flags & FLAG_BLOCKS == 0 {
+            return Err(Error::BadHeader("index without blocks"));
+        }
+
+        let max_word_size = input[7];
+        let full_policy = FullPolicy::from_id(input[8], input[9])
+            .ok_or(Error::BadHeader("unknown full policy"))?;
+        let growth = Growth::from_id(input[10]).ok_or(Error::BadHeader("unknown growth"))?;
+        let dictionary_id = u32::from_le_bytes(input[11..15].try_into().unwrap());
+        let original_size = u64::from_le_bytes(input[15..23].try_into().unwrap());
+        let checksum = (version >= 2).then(|| u32::from_le_bytes(input[23..27].try_into().unwrap()));
+        let payload_size = (version >= 3).then(|| u64::from_le_bytes(input[27..35].try_into().unwrap()));
+
+        Ok(Some(Header {
+            mode,
+            huffman : flags & FLAG_HUFFMAN != 0,
+            blocks : flags & FLAG_BLOCKS != 0,
+            indexed : flags & FLAG_INDEX != 0,
+            max_word_size,
+            full_policy,
+            growth,
+            dictionary_id : (flags & FLAG_PRESET != 0).then_some(dictionary_id),
+            original_size,
+            checksum,
+            payload_size,
+        }))
+    }
+}
+
+
+/// Compresses `input` with LZW, optionally followed by Huffman, behind a header.
+///
+/// The result is a single member: appending it to another compressed file
+/// makes one that decompresses to both originals in turn.
+pub fn compress(input : &[u8], config : &LzwConfig, huffman : HuffmanStage) -> Result<Vec<u8>, Error> {
+    check_preset(config)?;
+    let (payload, huffman) = encode_payload(input, config, huffman)?;
+    Ok(member(input, config, huffman, payload))
+}
+
+/// What both stages of `compress_with_stats` saw
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionStats {
+    pub lzw: LzwStats,
+    /// Size of the LZW stream in bytes
+    pub lzw_size: usize,
+    /// `None` if the Huffman stage didn't run
+    pub huffman: Option<HuffmanStats>,
+    /// Whether the Huffman stage's output was kept, which under
+    /// `HuffmanStage::IfSmaller` it only is if it came out smaller
+    pub huffman_kept: bool,
+}
+
+/// Same as `compress`, also collecting statistics from the encoders for
+/// tuning the settings to the data
+pub fn compress_with_stats(
+    input : &[u8],
+    config : &LzwConfig,
+    huffman : HuffmanStage,
+) -> Result<(Vec<u8>, CompressionStats), Error> {
+    check_preset(config)?;
+    let symbols = config.mode.symbols(input)?;
+    let (payload, lzw) = LzwEncoder::new(config.clone()).encode_with_stats(symbols)?;
+    let payload = payload.to_bytes();
+
+    let (huffman_payload, huffman_stats) = match huffman_stage(config, huffman) {
+        HuffmanStage::Off => (None, None),
+        _ => {
+            let (huffman_payload, stats) = HuffmanEncoder::new().encode_with_stats(&payload);
+            (Some(huffman_payload), Some(stats))
+        },
+    };
+    let lzw_size = payload.len();
+    let (payload, huffman_kept) = choose_payload(payload, huffman_payload, huffman);
+
+    let stats = CompressionStats { lzw, lzw_size, huffman : huffman_stats, huffman_kept };
+    Ok((member(input, config, huffman_kept, payload), stats))
+}
+
+/// `payload` behind a header and followed by the end marker
+fn member(input : &[u8], config : &LzwConfig, huffman : bool, payload : Vec<u8>) -> Vec<u8> {
+    let mut output = Header::new(input, config, huffman, false, payload.len()).to_bytes();
+    output.extend(payload);
+    output.extend(END_MARKER);
+    output
+}
+
+/// Compresses `input` in blocks of about `block_size` bytes, each with an
+/// encoder of its own, `threads` of them at a time.
+///
+/// Costs some compression for each dictionary that starts over, in return
+/// for blocks `decompress_parallel` can decode side by side. Each block gets
+/// the Huffman stage on its own, as `huffman` says. In text mode blocks end
+/// on character boundaries, so they may come out a few bytes shorter.
+pub fn compress_blocks(
+    input : &[u8],
+    config : &LzwConfig,
+    huffman : HuffmanStage,
+    block_size : usize,
+    threads : usize,
+) -> Result<Vec<u8>, Error> {
+    check_preset(config)?;
+    let blocks = split_blocks(input, config.mode, block_size);
+    let encoded = parallel_map(&blocks, threads, |block| encode_payload(block, config, huffman))
+        .into_iter()
+        .collect::<Result<Vec<(Vec<u8>, bool)>, Error>>()?;
+
+    let any_huffman = encoded.iter().any(|(_, huffman)| *huffman);
+    let payload_size = BlockIndex::size(blocks.len())
+        + encoded.iter().map(|(payload, _)| Frame::HEADER_SIZE + payload.len()).sum::<usize>();
+    let header = Header::new(input, config, any_huffman, true, payload_size);
+
+    let mut index = BlockIndex { blocks : Vec::with_capacity(blocks.len()) };
+    let (mut original, mut position) = (0, (header.size() + BlockIndex::size(blocks.len())) as u64);
+    for (block, (payload, _)) in blocks.iter().zip(&encoded) {
+        index.blocks.push((original, position));
+        original += block.len() as u64;
+        position += (Frame::HEADER_SIZE + payload.len()) as u64;
+    }
+
+    let mut output = header.to_bytes();
+    output.extend(index.to_bytes());
+    for (block, (payload, huffman)) in blocks.iter().zip(encoded) {
+        output.extend(Frame { original_size : block.len(), huffman, data : &payload }.header());
+        output.extend(payload);
+    }
+    output.extend(END_MARKER);
+    Ok(output)
+}
+
+fn check_preset(config : &LzwConfig) -> Result<(), Error> {
+    if config.preset.as_ref().is_some_and(|preset| preset.mode() != config.mode) {
+        Err(Error::BadDictionary("made for another mode"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs LZW, then Huffman as `huffman` says. Returns the payload and whether
+/// it went through Huffman.
+///
+/// Modes without an end-of-stream codeword never get the Huffman stage: what
+/// its padding decodes to would be read as more codewords.
+fn encode_payload(input : &[u8], config : &LzwConfig, huffman : HuffmanStage) -> Result<(Vec<u8>, bool), Error> {
+    let symbols = config.mode.symbols(input)?;
+    let payload = LzwEncoder::new(config.clone()).encode(symbols)?.to_bytes();
+    let huffman = huffman_stage(config, huffman);
+    let huffman_payload = (huffman != HuffmanStage::Off).then(|| HuffmanEncoder::new().encode(&payload));
+    Ok(choose_payload(payload, huffman_payload, huffman))
+}
+
+/// `huffman`, unless the stream has no end-of-stream codeword to stop
+/// the decoder before the Huffman stage's padding
+fn huffman_stage(config : &LzwConfig, huffman : HuffmanStage) -> HuffmanStage {
+    if config.mode.has_control_words() { huffman } else { HuffmanStage::Off }
+}
+
+/// Picks between the LZW stream and what the Huffman stage made of it,
+/// as `huffman` says. Also returns whether the Huffman output was picked.
+fn choose_payload(payload : Vec<u8>, huffman_payload : Option<Vec<u8>>, huffman : HuffmanStage) -> (Vec<u8>, bool) {
+    match (huffman_payload, huffman) {
+        (None, _) | (_, HuffmanStage::Off) => (payload, false),
+        (Some(huffman_payload), HuffmanStage::On) => (huffman_payload, true),
+        (Some(huffman_payload), HuffmanStage::IfSmaller) => {
+            if huffman_payload.len() < payload.len() {
+                (huffman_payload, true)
+            } else {
+                (payload, false)
+            }
+        },
+    }
+}
+
+/// Cuts `input` into pieces of `block_size` bytes, or a little less in text
+/// mode where a character would be split
+fn split_blocks(input : &[u8], mode : Mode, block_size : usize) -> Vec<&[u8]> {
+    let block_size = block_size.max(1);
+    let is_continuation = |index : usize| mode != Mode::Bytes && input.get(index).is_some_and(|b| b & 0xC0 == 0x80);
+
+    let mut blocks = vec![];
+    let mut start = 0;
+    while start < input.len() {
+        let mut end = (start + block_size).min(input.len());
+        while end > start && is_continuation(end) {
+            end -= 1;
+        }
+        // Blocks narrower than a character take all of it
+        if end == start {
+            end = start + 1;
+            while is_continuation(end) {
+                end += 1;
+            }
+        }
+        blocks.push(&input[start..end]);
+        start = end;
+    }
+    blocks
+}
+
+/// `f` over `items` on up to `threads` threads, results in order
+fn parallel_map<T: Sync, R: Send>(items : &[T], threads : usize, f : impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results = (0..items.len()).map(|_| None).collect::<Vec<Option<R>>>();
+    thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break done;
+                        };
+                        done.push((index, f(item)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for worker in workers {
+            for (index, result) in worker.join().expect("worker threads don't panic") {
+                results[index] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(|result| result.expect("every item is taken by a worker")).collect()
+}
+
+
+/// One block of a stream from `compress_blocks`.
+///
+/// Layout: original size (u32 LE), stored size (u32 LE), flags (1, only
+/// `FLAG_HUFFMAN`), then the stored data
+struct Frame<'a> {
+    original_size: usize,
+    huffman: bool,
+    data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    const HEADER_SIZE: usize = 9;
+
+    fn header(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Frame::HEADER_SIZE);
+        bytes.extend((self.original_size as u32).to_le_bytes());
+        bytes.extend((self.data.len() as u32).to_le_bytes());
+        bytes.push(if self.huffman { FLAG_HUFFMAN } else { 0 });
+        bytes
+    }
+
+    /// Reads the frame at the start of `bytes`, returning it and its size
+    fn parse(bytes : &'a [u8]) -> Result<(Frame<'a>, usize), Error> {
+        if bytes.len() < Frame::HEADER_SIZE {
+            return Err(Error::Corrupt("block header cut short"));
+        }
+        let original_size = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let stored_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let huffman = match bytes[8] {
+            0 => false,
+            FLAG_HUFFMAN => true,
+            _ => return Err(Error::Corrupt("unknown block flags")),
+        };
+
+        let data = bytes[Frame::HEADER_SIZE..]
+            .get(..stored_size)
+            .ok_or(Error::Corrupt("block cut short"))?;
+        Ok((Frame { original_size, huffman, data }, Frame::HEADER_SIZE + stored_size))
+    }
+
+    /// Splits the payload of a block stream into its frames
+    fn split(mut payload : &'a [u8]) -> Result<Vec<Frame<'a>>, Error> {
+        let mut frames = vec![];
+        while !payload.is_empty() {
+            let (frame, size) = Frame::parse(payload)?;
+            frames.push(frame);
+            payload = &payload[size..];
+        }
+        Ok(frames)
+    }
+
+    fn decode(&self, config : &LzwConfig) -> Result<Vec<u8>, Error> {
+        let output = decode_payload(self.data, self.huffman, config.clone(), Some(self.original_size as u64))?;
+        if output.len() != self.original_size {
+            return Err(Error::Corrupt("block decoded to the wrong size"));
+        }
+        Ok(output)
+    }
+}
+
+
+/// Where each block of a stream from `compress_blocks` starts, written
+/// between the header and the first frame when `FLAG_INDEX` is set.
+///
+/// Layout: block count (u32 LE), then for each block its offset in the
+/// original and the offset of its frame from the start of the member (u64 LE
+/// each)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockIndex {
+    /// `(original offset, stream offset)` of each block, in order
+    blocks: Vec<(u64, u64)>,
+}
+
+impl BlockIndex {
+    fn size(blocks : usize) -> usize {
+        4 + 16 * blocks
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BlockIndex::size(self.blocks.len()));
+        bytes.extend((self.blocks.len() as u32).to_le_bytes());
+        for (original, position) in &self.blocks {
+            bytes.extend(original.to_le_bytes());
+            bytes.extend(position.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads the index at the start of `payload`, returning it and the frames after it
+    fn parse(payload : &[u8]) -> Result<(BlockIndex, &[u8]), Error> {
+        let count = payload.get(..4).ok_or(Error::Corrupt("block index cut short"))?;
+        let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+        let size = count
+            .checked_mul(16)
+            .and_then(|entries| entries.checked_add(4))
+            .filter(|size| *size <= payload.len())
+            .ok_or(Error::Corrupt("block index cut short"))?;
+
+        let blocks = payload[4..size]
+            .chunks_exact(16)
+            .map(|entry| {
+                (
+                    u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                    u64::from_le_bytes(entry[8..].try_into().unwrap()),
+                )
+            })
+            .collect::<Vec<(u64, u64)>>();
+
+        let starts_at_zero = blocks.first().is_none_or(|(original, _)| *original == 0);
+        if !starts_at_zero || blocks.windows(2).any(|pair| pair[0].0 >= pair[1].0 || pair[0].1 >= pair[1].1) {
+            return Err(Error::Corrupt("block index out of order"));
+        }
+        Ok((BlockIndex { blocks }, &payload[size..]))
+    }
+
+    /// Builds the index of a stream written without one, by walking the
+    /// frames, which start `start` bytes into the member
+    fn scan(mut frames : &[u8], start : usize) -> Result<BlockIndex, Error> {
+        let mut index = BlockIndex { blocks : vec![] };
+        let (mut original, mut position) = (0, start);
+        while !frames.is_empty() {
+            let (frame, size) = Frame::parse(frames)?;
+            index.blocks.push((original, position as u64));
+            original += frame.original_size as u64;
+            position += size;
+            frames = &frames[size..];
+        }
+        Ok(index)
+    }
+
+    /// Size in the original of the block at `index`, given the size of the whole
+    fn block_size(&self, index : usize, original_size : u64) -> Option<u64> {
+        let start = self.blocks[index].0;
+        let end = self.blocks.get(index + 1).map_or(original_size, |(next, _)| *next);
+        end.checked_sub(start)
+    }
+}
+
+
+/// Splits `input` into its members, the output of `compress` or
+/// `compress_blocks` written one after another. Each is a compressed file of
+/// its own.
+///
+/// Members from before version 3 don't record where they end, so are taken to
+/// run to the end of the file. Headerless files are a single member.
+pub fn members(mut input : &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut members = vec![];
+    loop {
+        let header = match Header::parse(input)? {
+            Some(header) => header,
+            None if members.is_empty() => return Ok(vec![input]),
+            None => return Err(Error::Corrupt("data after the last member")),
+        };
+        let Some(payload_size) = header.payload_size else {
+            members.push(input);
+            return Ok(members);
+        };
+
+        let end = usize::try_from(payload_size)
+            .ok()
+            .and_then(|payload_size| payload_size.checked_add(header.size()))
+            .filter(|end| *end <= input.len())
+            .ok_or(Error::Corrupt("member cut short"))?;
+        if !input[end..].starts_with(&END_MARKER) {
+            return Err(Error::Corrupt("member end marker missing"));
+        }
+        let (member, rest) = input.split_at(end + END_MARKER.len());
+        members.push(member);
+
+        input = rest;
+        if input.is_empty() {
+            return Ok(members);
+        }
+    }
+}
+
+/// Reverses `compress` and `compress_blocks`, decoding each member of `input`
+/// in turn.
+///
+/// Headerless files are decoded the way older versions wrote them, which
+/// leaves it to the caller to say whether Huffman was applied. `preset` is
+/// only used if the header names it.
+///
+/// The output is checked against the size and checksum in the header, so
+/// fails with `Error::WrongSize` or `Error::ChecksumMismatch` on damaged
+/// files that still decode. Decoding stops with `Error::Corrupt` as soon as
+/// it passes the recorded size. Headerless files can't be checked.
+pub fn decompress(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+) -> Result<Vec<u8>, Error> {
+    decompress_parallel(input, legacy_huffman, preset, 1, Limits::none())
+}
+
+/// Same as `decompress`, decoding the blocks of streams from
+/// `compress_blocks` on up to `threads` threads, each decoder held to
+/// `limits`.
+///
+/// `limits.max_output` bounds the whole output, so members whose headers
+/// record more are refused before being decoded.
+pub fn decompress_parallel(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    threads : usize,
+    limits : Limits,
+) -> Result<Vec<u8>, Error> {
+    let mut output = vec![];
+    for member in members(input)? {
+        if let Some(header) = Header::parse(member)? {
+            limits.check_output((output.len() as u64).saturating_add(header.original_size))?;
+        }
+        output.extend(decompress_member(member, legacy_huffman, preset, threads, limits)?);
+        limits.check_output(output.len() as u64)?;
+    }
+    Ok(output)
+}
+
+fn decompress_member(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    threads : usize,
+    limits : Limits,
+) -> Result<Vec<u8>, Error> {
+    let stream = Stream::open(input, legacy_huffman, preset, limits)?;
+
+    let Some(header) = stream.header else {
+        return decode_payload(stream.payload, stream.huffman, stream.config, None);
+    };
+
+    let output = if header.blocks {
+        let frames = Frame::split(stream.payload)?;
+        if let Some(index) = &stream.index {
+            if *index != BlockIndex::scan(stream.payload, stream.payload_offset)? {
+                return Err(Error::Corrupt("block index doesn't match the blocks"));
+            }
+        }
+        let mut output = vec![];
+        for block in parallel_map(&frames, threads, |frame| frame.decode(&stream.config)) {
+            output.extend(block?);
+        }
+        output
+    } else {
+        decode_payload(stream.payload, stream.huffman, stream.config, Some(header.original_size))?
+    };
+
+    if output.len() as u64 != header.original_size {
+        return Err(Error::WrongSize { expected : header.original_size, found : output.len() as u64 });
+    }
+    if let Some(expected) = header.checksum {
+        let found = crc32(&output);
+        if found != expected {
+            return Err(Error::ChecksumMismatch { expected, found });
+        }
+    }
+    Ok(output)
+}
+
+/// Decodes `length` bytes of the original starting at `offset`, or fewer where
+/// that runs past the end. Takes the other arguments as `decompress` does.
+///
+/// Members the range doesn't reach aren't decoded, and of those from
+/// `compress_blocks` only the blocks overlapping the range are, found through
+/// their index. Other members are decoded in full and then cut down to the
+/// range, so `limits.max_output` bounds everything decoded on the way, as in
+/// `decompress_parallel`, not just what is returned. The checksum covers a
+/// whole member so isn't checked, but each block decoded is checked against
+/// its size.
+pub fn decompress_range(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    mut offset : u64,
+    mut length : u64,
+    limits : Limits,
+) -> Result<Vec<u8>, Error> {
+    let mut output = vec![];
+    let mut decoded = 0u64;
+    for member in members(input)? {
+        if length == 0 {
+            break;
+        }
+        let header = Header::parse(member)?;
+        let original_size = header.as_ref().map(|header| header.original_size);
+        if original_size.is_some_and(|size| offset >= size) {
+            offset -= original_size.unwrap();
+            continue;
+        }
+        if let Some(header) = header.filter(|header| !header.blocks) {
+            limits.check_output(decoded.saturating_add(header.original_size))?;
+        }
+
+        let (part, size) = member_range(member, legacy_huffman, preset, offset, length, limits)?;
+        decoded = decoded.saturating_add(size);
+        limits.check_output(decoded)?;
+        length -= part.len() as u64;
+        offset = 0;
+        output.extend(part);
+    }
+    Ok(output)
+}
+
+/// `decompress_range` on a single member, with the number of bytes decoded
+/// to get there
+fn member_range(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    offset : u64,
+    length : u64,
+    limits : Limits,
+) -> Result<(Vec<u8>, u64), Error> {
+    let stream = Stream::open(input, legacy_huffman, preset, limits)?;
+    let original_size = match &stream.header {
+        Some(header) if header.blocks => header.original_size,
+        _ => {
+            let output = decompress_member(input, legacy_huffman, preset, 1, limits)?;
+            let start = offset.min(output.len() as u64) as usize;
+            let end = offset.saturating_add(length).min(output.len() as u64) as usize;
+            return Ok((output[start..end].to_vec(), output.len() as u64));
+        },
+    };
+
+    let index = match stream.index {
+        Some(index) => index,
+        None => BlockIndex::scan(stream.payload, stream.payload_offset)?,
+    };
+    let end = offset.saturating_add(length).min(original_size);
+    if offset >= end {
+        return Ok((vec![], 0));
+    }
+
+    let first = index.blocks.partition_point(|(start, _)| *start <= offset).saturating_sub(1);
+    let mut output = vec![];
+    let mut decoded = 0;
+    for (i, &(start, position)) in index.blocks.iter().enumerate().skip(first) {
+        if start >= end {
+            break;
+        }
+        let size = index.block_size(i, original_size).ok_or(Error::Corrupt("block index out of order"))?;
+        let (frame, _) = usize::try_from(position)
+            .ok()
+            .and_then(|position| input.get(position..))
+            .ok_or(Error::Corrupt("block index points past the end"))
+            .and_then(Frame::parse)?;
+        if frame.original_size as u64 != size {
+            return Err(Error::Corrupt("block index doesn't match the blocks"));
+        }
+
+        let block = frame.decode(&stream.config)?;
+        decoded += size;
+        let from = offset.saturating_sub(start) as usize;
+        let to = (end - start).min(size) as usize;
+        output.extend(&block[from..to]);
+    }
+
+    if output.len() as u64 != end - offset {
+        return Err(Error::Corrupt("block index doesn't cover the range"));
+    }
+    Ok((output, decoded))
+}
+
+fn decode_payload(payload : &[u8], huffman : bool, config : LzwConfig, expected : Option<u64>) -> Result<Vec<u8>, Error> {
+    let mode = config.mode;
+    let (symbols, _) = decode_symbols(payload, huffman, config, expected)?;
+    let mut output = mode.to_bytes(&symbols);
+
+    // The recorded size is all that says where a stream without an
+    // end-of-stream codeword ends, the rest is padding
+    if let Some(expected) = expected.filter(|_| !mode.has_control_words()) {
+        output.truncate(expected as usize);
+    }
+    Ok(output)
+}
+
+/// Decodes a payload to symbols, counting its codewords by width as
+/// `LzwDecoder::decode_with_widths` does
+fn decode_symbols(
+    payload : &[u8],
+    huffman : bool,
+    config : LzwConfig,
+    expected : Option<u64>,
+) -> Result<(Vec<char>, Vec<usize>), Error> {
+    decode_capped(payload, huffman, config, expected, |decoder, words| {
+        let (symbols, _, widths) = decoder.decode_with_widths(words)?;
+        Ok((symbols, widths))
+    })
+}
+
+/// Runs `decode` over a payload, with the Huffman stage undone.
+///
+/// `expected` is the size in bytes the header or frame records. Symbols take
+/// a byte or more each, so the decoder is stopped once it has more symbols
+/// than that, and a damaged or hostile stream can't run on past it. Modes
+/// without an end-of-stream codeword get a word of padding on top.
+fn decode_capped<T>(
+    payload : &[u8],
+    huffman : bool,
+    mut config : LzwConfig,
+    expected : Option<u64>,
+    decode : impl FnOnce(LzwDecoder, Vec<IntType>) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let words = lzw_words(payload, huffman, config.limits)?;
+    let padding = if config.mode.has_control_words() { 0 } else { INTTYPE_BITS as u64 };
+    let cap = expected
+        .map(|expected| expected + padding)
+        .filter(|expected| config.limits.max_output.is_none_or(|max| *expected < max));
+    if cap.is_some() {
+        config.limits.max_output = cap;
+    }
+
+    match decode(LzwDecoder::new(config), words) {
+        Err(Error::LimitExceeded { limit : Limit::Output, max }) if Some(max) == cap => {
+            Err(Error::Corrupt("decodes to more than the recorded size"))
+        },
+        result => result,
+    }
+}
+
+/// The payload with the Huffman stage undone, as words for `LzwDecoder`.
+/// What Huffman decodes to isn't the output, so only the memory limit applies to it.
+fn lzw_words(payload : &[u8], huffman : bool, limits : Limits) -> Result<Vec<IntType>, Error> {
+    if huffman {
+        let limits = Limits { max_output : None, ..limits };
+        Ok(u8_to_usize(HuffmanDecoder::with_limits(limits).decode(payload)?))
+    } else {
+        Ok(u8_to_usize(payload.to_vec()))
+    }
+}
+
+
+/// What can be told about a compressed file without decoding it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspection {
+    /// `None` for headerless files
+    pub header: Option<Header>,
+    /// Size of the member, up to and including its end marker
+    pub compressed_size: usize,
+    /// What follows the header
+    pub payload_size: usize,
+    /// From the Huffman stage's frequency table, if there is one. Block
+    /// streams have a table per block and report none.
+    pub huffman: Option<HuffmanSummary>,
+    /// Number of blocks in streams from `compress_blocks`
+    pub blocks: Option<usize>,
+    /// Whether the blocks can be found through an index, for `decompress_range`
+    pub indexed: bool,
+}
+
+/// Reads the header and the Huffman frequency table of `input`, or of its
+/// first member if it has several, see `members`. As with `decompress`,
+/// `legacy_huffman` says whether a headerless file has the Huffman stage.
+pub fn inspect(input : &[u8], legacy_huffman : bool) -> Result<Inspection, Error> {
+    let input = members(input)?[0];
+    let header = Header::parse(input)?;
+    let mut payload = match &header {
+        Some(header) => header.payload(input)?,
+        None => input,
+    };
+    let blocks = header.as_ref().is_some_and(|header| header.blocks);
+    let indexed = header.as_ref().is_some_and(|header| header.indexed);
+    let payload_size = payload.len();
+    if indexed {
+        payload = BlockIndex::parse(payload)?.1;
+    }
+
+    let huffman = if !blocks && header.as_ref().map_or(legacy_huffman, |header| header.huffman) {
+        Some(HuffmanDecoder::summarize(payload).ok_or(Error::BadHeader("Huffman table cut short"))?)
+    } else {
+        None
+    };
+
+    Ok(Inspection {
+        header,
+        compressed_size : input.len(),
+        payload_size,
+        huffman,
+        blocks : if blocks { Some(Frame::split(payload)?.len()) } else { None },
+        indexed,
+    })
+}
+
+/// Decodes the LZW stream of `input` to count its codewords by width: the
+/// count at index N is for codewords N bits wide. Takes the same arguments
+/// as `decompress_parallel`, and counts over every member.
+pub fn code_widths(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    limits : Limits,
+) -> Result<Vec<usize>, Error> {
+    let mut total = vec![];
+    for member in members(input)? {
+        let stream = Stream::open(member, legacy_huffman, preset, limits)?;
+        let count = |payload : &[u8], huffman : bool, expected : Option<u64>| {
+            Ok::<Vec<usize>, Error>(decode_symbols(payload, huffman, stream.config.clone(), expected)?.1)
+        };
+
+        let parts = match &stream.header {
+            Some(header) if header.blocks => Frame::split(stream.payload)?
+                .iter()
+                .map(|frame| count(frame.data, frame.huffman, Some(frame.original_size as u64)))
+                .collect(),
+            header => vec![count(stream.payload, stream.huffman, header.as_ref().map(|header| header.original_size))],
+        };
+        for widths in parts {
+            let widths = widths?;
+            total.resize(total.len().max(widths.len()), 0);
+            for (sum, n) in total.iter_mut().zip(widths) {
+                *sum += n;
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Decodes the LZW streams of `input` for the dictionary each ends with, and
+/// the tree of its Huffman stage. Takes the same arguments as
+/// `decompress_parallel`, but only reads the first member. Block streams
+/// have a dictionary per block, the others just the one.
+pub fn dictionaries(
+    input : &[u8],
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    limits : Limits,
+) -> Result<Vec<DictionaryDump>, Error> {
+    let stream = Stream::open(members(input)?[0], legacy_huffman, preset, limits)?;
+    let dump = |payload : &[u8], huffman : bool, expected : Option<u64>| {
+        let entries = decode_capped(payload, huffman, stream.config.clone(), expected, |decoder, words| {
+            Ok(decoder.decode_with_dictionary(words)?.2)
+        })?;
+        let huffman = if huffman {
+            Some(HuffmanDecoder::tree(payload).ok_or(Error::Corrupt("Huffman frequency table cut short"))?)
+        } else {
+            None
+        };
+        Ok(DictionaryDump { mode : stream.config.mode, entries, huffman })
+    };
+
+    match &stream.header {
+        Some(header) if header.blocks => Frame::split(stream.payload)?
+            .iter()
+            .map(|frame| dump(frame.data, frame.huffman, Some(frame.original_size as u64)))
+            .collect(),
+        header => Ok(vec![dump(stream.payload, stream.huffman, header.as_ref().map(|header| header.original_size))?]),
+    }
+}
+
+
+/// A compressed file taken apart for decoding
+struct Stream<'a> {
+    /// `None` for headerless files
+    header: Option<Header>,
+    /// Decoder configuration the header calls for
+    config: LzwConfig,
+    huffman: bool,
+    /// Where the blocks start, for block streams that have an index
+    index: Option<BlockIndex>,
+    /// What follows the header, and the index if there is one, up to the end marker
+    payload: &'a [u8],
+    /// Where `payload` starts in the member
+    payload_offset: usize,
+}
+
+impl<'a> Stream<'a> {
+    fn open(
+        input : &'a [u8],
+        legacy_huffman : bool,
+        preset : Option<&Arc<PresetDictionary>>,
+        limits : Limits,
+    ) -> Result<Self, Error> {
+        let Some(header) = Header::parse(input)? else {
+            // No end-of-stream codeword, so padding bits come out as trailing symbols
+            let config = LzwConfig {
+                max_word_size : INTTYPE_BITS,
+                limits,
+                ..LzwConfig::new(Mode::Legacy)
+            };
+            return Ok(Stream { header : None, config, huffman : legacy_huffman, index : None, payload : input, payload_offset : 0 });
+        };
+
+        let preset = match (header.dictionary_id, preset) {
+            (None, _) => None,
+            (Some(expected), None) => return Err(Error::MissingDictionary(expected)),
+            (Some(expected), Some(preset)) => {
+                let found = preset.id();
+                if found != expected {
+                    return Err(Error::WrongDictionary { expected, found });
+                }
+                Some(preset.clone())
+            },
+        };
+
+        let config = LzwConfig {
+            max_word_size : header.max_word_size.into(),
+            full_policy : header.full_policy,
+            growth : header.growth,
+            preset,
+            limits,
+            ..LzwConfig::new(header.mode)
+        };
+        let (huffman, mut payload) = (header.huffman, header.payload(input)?);
+        let mut payload_offset = header.size();
+        let index = if header.indexed {
+            let (index, frames) = BlockIndex::parse(payload)?;
+            payload_offset += payload.len() - frames.len();
+            payload = frames;
+            Some(index)
+        } else {
+            None
+        };
+        Ok(Stream { header : Some(header), config, huffman, index, payload, payload_offset })
+    }
+}
+
+
+#[cfg(test)]
+mod container_test {
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use crate::container::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            mode : Mode::Text,
+            huffman : true,
+            blocks : true,
+            indexed : true,
+            max_word_size : 12,
+            full_policy : FullPolicy::AdaptiveClear { threshold : 5 },
+            growth : Growth::Lzap,
+            dictionary_id : Some(0xDEAD_BEEF),
+            original_size : 8200,
+            checksum : Some(0x1234_5678),
+            payload_size : Some(4000),
+        };
+        let bytes = header.to_bytes();
+
+        assert_eq!(bytes.len(), Header::SIZE);
+        assert_eq!(Header::parse(&bytes).unwrap(), Some(header.clone()));
+
+        let version_2 = Header { payload_size : None, ..header.clone() };
+        let bytes = version_2.to_bytes();
+
+        assert_eq!((bytes.len(), bytes[4]), (Header::SIZE_V2, 2));
+        assert_eq!(Header::parse(&bytes).unwrap(), Some(version_2));
+
+        let version_1 = Header { checksum : None, payload_size : None, ..header };
+        let bytes = version_1.to_bytes();
+
+        assert_eq!((bytes.len(), bytes[4]), (Header::SIZE_V1, 1));
+        assert_eq!(Header::parse(&bytes).unwrap(), Some(version_1));
+        assert!(matches!(Header::parse(&bytes[..Header::SIZE_V1 - 1]), Err(Error::BadHeader(_))));
+    }
+
+    #[test]
+    fn inspect_reads_without_decoding() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let compressed = compress(text, &LzwConfig::new(Mode::Text), HuffmanStage::On).unwrap();
+        let inspection = inspect(&compressed, false).unwrap();
+
+        let header = inspection.header.unwrap();
+        assert_eq!((header.original_size, header.checksum), (text.len() as u64, Some(crc32(text))));
+        assert_eq!(inspection.payload_size + Header::SIZE + END_MARKER.len(), compressed.len());
+
+        let huffman = inspection.huffman.unwrap();
+        assert!(huffman.shortest <= huffman.longest && huffman.symbols <= 256);
+
+        // The first codeword is as wide as the initial dictionary needs, none wider than allowed
+        let widths = code_widths(&compressed, false, None, Limits::none()).unwrap();
+        let narrowest = (usize::BITS - (Mode::Text.initial_words() - 1).leading_zeros()) as usize;
+        assert_eq!(widths.iter().position(|count| *count > 0), Some(narrowest));
+        assert!(widths.len() <= 17);
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let text = include_str!("../folktale.txt").as_bytes();
+
+        for huffman in [HuffmanStage::Off, HuffmanStage::On] {
+            let compressed = compress(text, &LzwConfig::new(Mode::Bytes), huffman).unwrap();
+
+            let mut wrong_size = compressed.clone();
+            wrong_size[15] ^= 1;
+            assert!(matches!(decompress(&wrong_size, false, None), Err(Error::WrongSize { .. })));
+
+            let mut wrong_checksum = compressed.clone();
+            wrong_checksum[23] ^= 1;
+            assert!(matches!(decompress(&wrong_checksum, false, None), Err(Error::ChecksumMismatch { .. })));
+
+            for position in (Header::SIZE..compressed.len()).step_by(97) {
+                let mut damaged = compressed.clone();
+                damaged[position] ^= 0x24;
+                assert!(decompress(&damaged, false, None).is_err(), "{huffman:?}, byte {position}");
+            }
+            assert!(decompress(&compressed[..compressed.len() - 10], false, None).is_err());
+        }
+    }
+
+    /// Minimised hostile inputs, and truncations of every kind of stream
+    #[test]
+    fn hostile_input_fails_cleanly() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let compressed = compress(text, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off).unwrap();
+
+        // A header recording fewer bytes than the stream holds
+        let mut undersized = compressed.clone();
+        undersized[15..23].copy_from_slice(&10u64.to_le_bytes());
+        assert!(matches!(
+            decompress(&undersized, false, None),
+            Err(Error::Corrupt("decodes to more than the recorded size"))
+        ));
+
+        // A block index claiming more blocks than the file could hold
+        let blocks = compress_blocks(text, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off, 1000, 1).unwrap();
+        let mut long_index = blocks.clone();
+        long_index[Header::SIZE..Header::SIZE + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress(&long_index, false, None).is_err());
+
+        // Huffman streams with no room for their frequency table, or only room for it
+        let mut tableless = Header::new(b"", &LzwConfig::new(Mode::Bytes), true, false, 3).to_bytes();
+        tableless.extend(b"abc");
+        tableless.extend(END_MARKER);
+        assert!(matches!(decompress(&tableless, false, None), Err(Error::Corrupt(_))));
+        assert!(decompress(&[0; 512], true, None).unwrap().is_empty());
+
+        let huffman = compress(&text[..300], &LzwConfig::new(Mode::Text), HuffmanStage::On).unwrap();
+        for stream in [&compressed[..], &blocks[..], &huffman[..]] {
+            for length in (Header::SIZE..stream.len()).step_by(7) {
+                assert!(decompress(&stream[..length], false, None).is_err(), "{length} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip_on_any_thread_count() {
+        let text = "Det var en gang — «😀» — en Fattigmann. ".repeat(40);
+        let text = text.as_bytes();
+
+        for mode in [Mode::Bytes, Mode::Text] {
+            for block_size in [1, 3, 100, 5000] {
+                let config = LzwConfig::new(mode);
+                let compressed = compress_blocks(text, &config, HuffmanStage::IfSmaller, block_size, 4).unwrap();
+
+                let blocks = inspect(&compressed, false).unwrap().blocks.unwrap();
+                assert_eq!(blocks, split_blocks(text, mode, block_size).len());
+                assert!(blocks >= text.len().div_ceil(block_size).min(text.len()) / 4);
+
+                assert_eq!(decompress(&compressed, false, None).unwrap(), text);
+                assert_eq!(decompress_parallel(&compressed, false, None, 3, Limits::none()).unwrap(), text);
+            }
+        }
+
+        let empty = compress_blocks(b"", &LzwConfig::new(Mode::Bytes), HuffmanStage::On, 10, 2).unwrap();
+        assert!(decompress_parallel(&empty, false, None, 2, Limits::none()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ranges_decode_only_their_blocks() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let config = LzwConfig::new(Mode::Bytes);
+        let blocks = compress_blocks(text, &config, HuffmanStage::IfSmaller, 1000, 2).unwrap();
+        let single = compress(text, &config, HuffmanStage::Off).unwrap();
+        assert!(inspect(&blocks, false).unwrap().indexed);
+
+        let size = text.len() as u64;
+        for (offset, length) in [(0, 0), (0, 10), (995, 10), (1000, 1000), (1500, 3000), (size - 5, 100), (size, 1), (size + 10, 5), (0, u64::MAX)] {
+            let expected = &text[offset.min(size) as usize..offset.saturating_add(length).min(size) as usize];
+            assert_eq!(decompress_range(&blocks, false, None, offset, length, Limits::none()).unwrap(), expected, "{offset}+{length}");
+            assert_eq!(decompress_range(&single, false, None, offset, length, Limits::none()).unwrap(), expected, "{offset}+{length}");
+        }
+
+        // Damage to a block outside the range goes unseen, unlike when decoding it all
+        let mut damaged = blocks.clone();
+        let last = damaged.len() - END_MARKER.len() - 3;
+        damaged[last] ^= 0x55;
+        assert_eq!(decompress_range(&damaged, false, None, 0, 100, Limits::none()).unwrap(), &text[..100]);
+        assert!(decompress(&damaged, false, None).is_err());
+    }
+
+    #[test]
+    fn block_streams_without_an_index_still_decode() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let compressed = compress_blocks(text, &LzwConfig::new(Mode::Text), HuffmanStage::On, 500, 1).unwrap();
+        let (index, frames) = BlockIndex::parse(&compressed[Header::SIZE..compressed.len() - END_MARKER.len()]).unwrap();
+
+        let mut header = Header::parse(&compressed).unwrap().unwrap();
+        header.indexed = false;
+        header.payload_size = Some(frames.len() as u64);
+        let mut unindexed = header.to_bytes();
+        unindexed.extend(frames);
+        unindexed.extend(END_MARKER);
+
+        let scanned = BlockIndex::scan(frames, Header::SIZE).unwrap();
+        assert!(scanned.blocks.iter().map(|(original, _)| original).eq(index.blocks.iter().map(|(original, _)| original)));
+        assert_eq!(decompress(&unindexed, false, None).unwrap(), text);
+        assert_eq!(decompress_range(&unindexed, false, None, 700, 600, Limits::none()).unwrap(), &text[700..1300]);
+        assert!(!inspect(&unindexed, false).unwrap().indexed);
+
+        // An index pointing somewhere else is caught either way
+        let mut damaged = compressed.clone();
+        damaged[Header::SIZE + 4 + 16 + 8] ^= 1;
+        assert!(matches!(decompress(&damaged, false, None), Err(Error::Corrupt(_))));
+        assert!(matches!(decompress_range(&damaged, false, None, 600, 10, Limits::none()), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn members_decode_in_turn() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let (first, second) = text.split_at(3000);
+
+        let mut file = compress(first, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off).unwrap();
+        file.extend(compress_blocks(second, &LzwConfig::new(Mode::Text), HuffmanStage::On, 1000, 2).unwrap());
+
+        assert_eq!(members(&file).unwrap().len(), 2);
+        assert_eq!(decompress(&file, false, None).unwrap(), text);
+        assert_eq!(decompress_range(&file, false, None, 2990, 20, Limits::none()).unwrap(), &text[2990..3010]);
+        assert_eq!(decompress_range(&file, false, None, 5000, 100, Limits::none()).unwrap(), &text[5000..5100]);
+
+        // Reaching into the second member's first block takes all of the first member
+        let output = |max| Limits { max_output : Some(max), ..Limits::none() };
+        assert!(decompress_range(&file, false, None, 2990, 20, output(4000)).is_ok());
+        assert!(matches!(
+            decompress_range(&file, false, None, 2990, 20, output(3999)),
+            Err(Error::LimitExceeded { limit : Limit::Output, max : 3999 })
+        ));
+
+        // Members from before version 3 run to the end, so can only come last
+        let old = compress(second, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off).unwrap();
+        let header = Header { payload_size : None, ..Header::parse(&old).unwrap().unwrap() };
+        let mut with_old = compress(first, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off).unwrap();
+        with_old.extend(header.to_bytes());
+        with_old.extend(&old[Header::SIZE..old.len() - END_MARKER.len()]);
+        assert_eq!(decompress(&with_old, false, None).unwrap(), text);
+
+        let mut trailing = file.clone();
+        trailing.extend(b"junk");
+        assert!(matches!(decompress(&trailing, false, None), Err(Error::Corrupt(_))));
+        assert!(matches!(decompress(&file[..file.len() - 2], false, None), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn limits_refuse_large_members_before_decoding() {
+        use crate::limits::Limit;
+
+        let text = include_str!("../folktale.txt").as_bytes();
+        let config = LzwConfig::new(Mode::Bytes);
+        let mut file = compress(text, &config, HuffmanStage::Off).unwrap();
+        file.extend(compress(text, &config, HuffmanStage::On).unwrap());
+        let decode = |limits| decompress_parallel(&file, false, None, 1, limits);
+
+        let size = text.len() as u64;
+        let output = |max| Limits { max_output : Some(max), ..Limits::none() };
+        assert!(matches!(decode(output(size - 1)), Err(Error::LimitExceeded { limit : Limit::Output, .. })));
+        assert!(matches!(decode(output(2 * size - 1)), Err(Error::LimitExceeded { limit : Limit::Output, .. })));
+        assert_eq!(decode(output(2 * size)).unwrap().len() as u64, 2 * size);
+
+        // The rest reach the decoders
+        let entries = Limits { max_entries : Some(300), ..Limits::none() };
+        assert!(matches!(decode(entries), Err(Error::LimitExceeded { limit : Limit::Entries, .. })));
+        let memory = Limits { max_memory : Some(20_000), ..Limits::none() };
+        assert!(matches!(decode(memory), Err(Error::LimitExceeded { limit : Limit::Memory, .. })));
+    }
+
+    #[test]
+    fn text_blocks_end_on_characters() {
+        let text = "aø😀b".as_bytes();
+        let pieces = |mode, size| split_blocks(text, mode, size).iter().map(|b| b.len()).collect::<Vec<usize>>();
+
+        assert_eq!(pieces(Mode::Bytes, 3), [3, 3, 2]);
+        assert_eq!(pieces(Mode::Text, 3), [3, 4, 1]);
+        assert_eq!(pieces(Mode::Text, 1), [1, 2, 4, 1]);
+        assert_eq!(pieces(Mode::Text, 1).iter().sum::<usize>(), text.len());
+    }
+
+    #[test]
+    fn headerless_is_legacy() {
+        assert_eq!(Header::parse(b"not a header").unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip_keeps_case_and_bytes() {
+        let text = "Det var en gang en Fattigmann.\nHan hadde TRE sønner!".as_bytes();
+        for huffman in [HuffmanStage::Off, HuffmanStage::On] {
+            let compressed = compress(text, &LzwConfig::new(Mode::Text), huffman).unwrap();
+            assert_eq!(decompress(&compressed, false, None).unwrap(), text);
+        }
+
+        let binary = [0u8, 255, 13, 10, 0xC3, 0x28, 0, 0, 0, 7];
+        for huffman in [HuffmanStage::Off, HuffmanStage::On] {
+            let compressed = compress(&binary, &LzwConfig::new(Mode::Bytes), huffman).unwrap();
+            assert_eq!(decompress(&compressed, false, None).unwrap(), binary);
+        }
+    }
+
+    #[test]
+    fn text_mode_escapes_unknown_symbols() {
+        // Escapes at the start, back to back, repeated and mid-sequence
+        let text = "«Ærlig talt» — 😀😀 sa han, 😀 «igjen»…".as_bytes();
+        for huffman in [HuffmanStage::Off, HuffmanStage::On] {
+            let compressed = compress(text, &LzwConfig::new(Mode::Text), huffman).unwrap();
+            assert_eq!(decompress(&compressed, false, None).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn legacy_mode_has_no_escape() {
+        assert!(matches!(
+            LzwEncoder::new(LzwConfig::new(Mode::Legacy)).encode("ab!".chars().collect()),
+            Err(Error::UnknownSymbol('!'))
+        ));
+    }
+
+    #[test]
+    fn legacy_mode_ends_at_the_recorded_size() {
+        // Padding decodes as spaces, and Huffman padding as anything at all
+        let text = b"  a qbbe jcaadefb biag";
+        let compressed = compress(text, &LzwConfig::new(Mode::Legacy), HuffmanStage::On).unwrap();
+
+        assert!(!Header::parse(&compressed).unwrap().unwrap().huffman);
+        assert_eq!(decompress(&compressed, false, None).unwrap(), text);
+    }
+
+    /// Files written by each version of the format, see `VERSION`. All of
+    /// them hold `golden/input.txt`, the preset ones made with
+    /// `golden/dictionary.lzwd`.
+    const GOLDEN: [(&str, &[u8]); 16] = [
+        ("v1-bytes", include_bytes!("../golden/v1-bytes.lzw")),
+        ("v1-bytes-9", include_bytes!("../golden/v1-bytes-9.lzw")),
+        ("v1-bytes-dict", include_bytes!("../golden/v1-bytes-dict.lzw")),
+        ("v1-bytes-lzmw-clear", include_bytes!("../golden/v1-bytes-lzmw-clear.lzw")),
+        ("v1-text-hm", include_bytes!("../golden/v1-text-hm.lzw")),
+        ("v1-text-lzap-lru", include_bytes!("../golden/v1-text-lzap-lru.lzw")),
+        ("v2-bytes", include_bytes!("../golden/v2-bytes.lzw")),
+        ("v2-bytes-blocks-hm", include_bytes!("../golden/v2-bytes-blocks-hm.lzw")),
+        ("v2-bytes-dict", include_bytes!("../golden/v2-bytes-dict.lzw")),
+        ("v2-text-hm", include_bytes!("../golden/v2-text-hm.lzw")),
+        ("v2-text-lzap-blocks", include_bytes!("../golden/v2-text-lzap-blocks.lzw")),
+        ("v3-bytes", include_bytes!("../golden/v3-bytes.lzw")),
+        ("v3-bytes-blocks-hm", include_bytes!("../golden/v3-bytes-blocks-hm.lzw")),
+        ("v3-bytes-dict", include_bytes!("../golden/v3-bytes-dict.lzw")),
+        ("v3-text-hm", include_bytes!("../golden/v3-text-hm.lzw")),
+        ("v3-text-lzap-lru", include_bytes!("../golden/v3-text-lzap-lru.lzw")),
+    ];
+
+    #[test]
+    fn every_format_version_still_decodes() {
+        let input = include_bytes!("../golden/input.txt");
+        let preset = Arc::new(PresetDictionary::parse(include_bytes!("../golden/dictionary.lzwd")).unwrap());
+
+        for (name, file) in GOLDEN {
+            let header = Header::parse(file).unwrap().unwrap();
+            assert_eq!(format!("v{}", header.version()), name[..2], "{name}");
+            assert_eq!(header.huffman, name.contains("hm"), "{name}");
+
+            assert_eq!(decompress(file, false, Some(&preset)).unwrap(), input, "{name}");
+            assert_eq!(decompress_range(file, false, Some(&preset), 1000, 20, Limits::none()).unwrap(), input[1000..1020], "{name}");
+            assert!(inspect(file, false).is_ok() && code_widths(file, false, Some(&preset), Limits::none()).is_ok(), "{name}");
+        }
+
+        // The folktale, then the legacy text appended as a second member
+        let members = include_bytes!("../golden/v3-members.lzw");
+        let legacy = include_bytes!("../golden/legacy.txt");
+        assert_eq!(decompress(members, false, None).unwrap(), [&input[..], &legacy[..]].concat());
+    }
+
+    #[test]
+    fn dictionaries_come_from_each_block() {
+        let input = include_bytes!("../golden/input.txt");
+        let config = LzwConfig::new(Mode::Bytes);
+
+        let dumps = dictionaries(&compress(input, &config, HuffmanStage::On).unwrap(), false, None, Limits::none()).unwrap();
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0].huffman.is_some());
+        let phrases = dumps[0].entries.iter().map(|entry| entry.phrase.len()).sum::<usize>();
+        assert!(phrases > input.len());
+
+        let blocks = compress_blocks(input, &config, HuffmanStage::Off, 1000, 1).unwrap();
+        let dumps = dictionaries(&blocks, false, None, Limits::none()).unwrap();
+        assert_eq!(dumps.len(), 3);
+        for dump in &dumps {
+            assert_eq!(dump.mode, Mode::Bytes);
+            assert!(dump.huffman.is_none());
+            assert_eq!(dump.entries[usize::from(b'a')].phrase, ['a']);
+        }
+
+        let limits = Limits { max_entries : Some(300), ..Limits::none() };
+        assert!(matches!(dictionaries(&blocks, false, None, limits), Err(Error::LimitExceeded { .. })));
+    }
+
+    #[test]
+    fn headerless_legacy_files_still_decode() {
+        // Nothing marks where they end, so the padding decodes too: as
+        // spaces, or with Huffman as whatever its own padding decodes to
+        let text = include_bytes!("../golden/legacy.txt");
+
+        let plain = decompress(include_bytes!("../golden/legacy.lzw"), false, None).unwrap();
+        assert!(plain.starts_with(text) && plain[text.len()..].iter().all(|b| *b == b' '));
+
+        let huffman = decompress(include_bytes!("../golden/legacy-hm.lzw"), true, None).unwrap();
+        assert!(huffman.starts_with(text) && huffman.len() < text.len() + 64);
+    }
+
+    #[test]
+    fn text_mode_rejects_invalid_utf8() {
+        assert!(matches!(compress(&[0xC3, 0x28], &LzwConfig::new(Mode::Text), HuffmanStage::Off), Err(Error::InvalidUtf8)));
+    }
+
+
+    /// Arbitrary input for `mode`, a short pattern over and over, and the
+    /// cScSc case (KwKwK), where a codeword is read right as it's made
+    fn input(mode : Mode) -> impl Strategy<Value = Vec<u8>> {
+        let symbol = match mode {
+            Mode::Bytes => any::<u8>().prop_map(char::from).boxed(),
+            Mode::Text => prop_oneof![select(mode.alphabet()), any::<char>()].boxed(),
+            Mode::Legacy => select(mode.alphabet()).boxed(),
+        };
+        let random = vec(symbol.clone(), 0..1000);
+        let repeated = (vec(symbol.clone(), 1..6), 1..300usize).prop_map(|(pattern, times)| pattern.repeat(times));
+        let cscsc = (symbol.clone(), vec(symbol, 0..4), 1..50usize).prop_map(|(c, s, times)| {
+            let mut symbols = [&[c], &s[..]].concat().repeat(times);
+            symbols.push(c);
+            symbols
+        });
+        prop_oneof![random, repeated, cscsc].prop_map(move |symbols| mode.to_bytes(&symbols))
+    }
+
+    /// A configuration for `mode` along with input for it
+    fn config_and_input() -> impl Strategy<Value = (LzwConfig, Vec<u8>)> {
+        select(vec![Mode::Bytes, Mode::Text, Mode::Legacy]).prop_flat_map(|mode| {
+            let full_policy = select(vec![
+                FullPolicy::Freeze,
+                FullPolicy::Clear,
+                FullPolicy::AdaptiveClear { threshold : 0 },
+                FullPolicy::Lru,
+            ]);
+            let growth = select(vec![Growth::Classic, Growth::Lzmw, Growth::Lzap]);
+            let parsing = select(vec![Parsing::Greedy, Parsing::Flexible]);
+            let preset = option::of(vec(vec(select(mode.alphabet()), 2..6), 1..20))
+                .prop_map(move |phrases| phrases.and_then(|phrases| PresetDictionary::new(mode, phrases).ok()).map(Arc::new));
+
+            let config = (9..=16usize, full_policy, growth, parsing, preset).prop_map(
+                move |(max_word_size, full_policy, growth, parsing, preset)| LzwConfig {
+                    max_word_size,
+                    full_policy,
+                    growth,
+                    parsing,
+                    preset,
+                    ..LzwConfig::new(mode)
+                },
+            );
+            (config, input(mode))
+        })
+    }
+
+    fn huffman() -> impl Strategy<Value = HuffmanStage> {
+        select(vec![HuffmanStage::Off, HuffmanStage::On, HuffmanStage::IfSmaller])
+    }
+
+    proptest! {
+        #[test]
+        fn every_pipeline_round_trips((config, input) in config_and_input(), huffman in huffman()) {
+            let compressed = compress(&input, &config, huffman).unwrap();
+            prop_assert_eq!(decompress(&compressed, false, config.preset.as_ref()).unwrap(), input);
+        }
+
+        #[test]
+        fn stats_leave_the_output_alone((config, input) in config_and_input(), huffman in huffman()) {
+            let (compressed, stats) = compress_with_stats(&input, &config, huffman).unwrap();
+            prop_assert_eq!(&compressed, &compress(&input, &config, huffman).unwrap());
+
+            let header = Header::parse(&compressed).unwrap().unwrap();
+            prop_assert_eq!(stats.huffman_kept, header.huffman);
+            let huffman_ran = huffman != HuffmanStage::Off && config.mode.has_control_words();
+            prop_assert_eq!(stats.huffman.is_some(), huffman_ran);
+            if !header.huffman {
+                prop_assert_eq!(stats.lzw_size, header.payload(&compressed).unwrap().len());
+            }
+        }
+
+        #[test]
+        fn blocks_members_and_ranges_round_trip(
+            (config, input) in config_and_input(),
+            huffman in huffman(),
+            block_size in 1..400usize,
+            threads in 1..4usize,
+            (offset, length) in (0..1200u64, 0..1200u64),
+        ) {
+            let preset = config.preset.as_ref();
+            let blocks = compress_blocks(&input, &config, huffman, block_size, threads).unwrap();
+            prop_assert_eq!(&decompress_parallel(&blocks, false, preset, threads, Limits::none()).unwrap(), &input);
+
+            // The same input again as a second, single-stream member
+            let mut members = blocks.clone();
+            members.extend(compress(&input, &config, huffman).unwrap());
+            let twice = [&input[..], &input[..]].concat();
+            prop_assert_eq!(&decompress_parallel(&members, false, preset, threads, Limits::none()).unwrap(), &twice);
+
+            let end = offset.saturating_add(length).min(twice.len() as u64) as usize;
+            let expected = &twice[(offset as usize).min(end)..end];
+            prop_assert_eq!(&decompress_range(&members, false, preset, offset, length, Limits::none()).unwrap()[..], expected);
+        }
+    }
+}
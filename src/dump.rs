@@ -0,0 +1,195 @@
+//! The dictionary an LZW stream ends with, and the tree of its Huffman stage,
+//! as JSON and Graphviz DOT, for seeing why a file compresses the way it does.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::huffman::HuffmanCode;
+use crate::lzw::{DictionaryEntry, Mode};
+use crate::IntType;
+
+/// What `container::dictionaries` found in one LZW stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictionaryDump {
+    pub mode: Mode,
+    /// Every entry, the alphabet included, in codeword order
+    pub entries: Vec<DictionaryEntry>,
+    /// `None` if the stream didn't go through the Huffman stage
+    pub huffman: Option<Vec<HuffmanCode>>,
+}
+
+impl DictionaryDump {
+    /// Phrases are strings of their symbols: in bytes mode each byte is the
+    /// character with its value.
+    pub fn to_json(&self) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "    {{\"codeword\": {}, \"width\": {}, \"phrase\": {}, \"uses\": {}}}",
+                    entry.codeword,
+                    entry.width,
+                    json_string(&entry.phrase.iter().collect::<String>()),
+                    entry.uses,
+                )
+            })
+            .collect::<Vec<String>>();
+
+        let huffman = match &self.huffman {
+            Some(codes) => {
+                let codes = codes
+                    .iter()
+                    .map(|code| {
+                        format!(
+                            "    {{\"byte\": {}, \"count\": {}, \"codeword\": \"{}\"}}",
+                            code.byte_value,
+                            code.count,
+                            code.codeword,
+                        )
+                    })
+                    .collect::<Vec<String>>();
+                format!("[\n{}\n  ]", codes.join(",\n"))
+            },
+            None => "null".to_string(),
+        };
+
+        format!(
+            "{{\n  \"mode\": \"{}\",\n  \"entries\": [\n{}\n  ],\n  \"huffman\": {huffman}\n}}\n",
+            self.mode.name(),
+            entries.join(",\n"),
+        )
+    }
+
+    /// The dictionary as a trie, each entry hanging off the longest other
+    /// entry it starts with, and the Huffman tree next to it
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec!["digraph dictionary {".to_string(), "  node [shape=box];".to_string()];
+
+        lines.push("  subgraph cluster_lzw {".to_string());
+        lines.push(format!("    label={};", dot_string(&format!("LZW dictionary, {} entries", self.entries.len()))));
+        lines.push("    lzw [label=\"\", shape=point];".to_string());
+
+        let codewords = self
+            .entries
+            .iter()
+            .rev()
+            .map(|entry| (&entry.phrase[..], entry.codeword))
+            .collect::<BTreeMap<&[char], IntType>>();
+        for entry in &self.entries {
+            let phrase = entry.phrase.iter().collect::<String>();
+            let label = format!("{}\n{phrase:?}\n{} uses, {} bits", entry.codeword, entry.uses, entry.width);
+            lines.push(format!("    w{} [label={}];", entry.codeword, dot_string(&label)));
+
+            let parent = (1..entry.phrase.len())
+                .rev()
+                .find_map(|length| Some((codewords.get(&entry.phrase[..length])?, length)));
+            let (parent, suffix) = match parent {
+                Some((parent, length)) => (format!("w{parent}"), &entry.phrase[length..]),
+                None => ("lzw".to_string(), &entry.phrase[..]),
+            };
+            let suffix = format!("{:?}", suffix.iter().collect::<String>());
+            lines.push(format!("    {parent} -> w{} [label={}];", entry.codeword, dot_string(&suffix)));
+        }
+        lines.push("  }".to_string());
+
+        if let Some(codes) = &self.huffman {
+            lines.push("  subgraph cluster_huffman {".to_string());
+            lines.push("    label=\"Huffman tree\";".to_string());
+            lines.push("    h [label=\"\", shape=point];".to_string());
+
+            let branches = codes
+                .iter()
+                .flat_map(|code| (1..code.codeword.len()).map(|length| &code.codeword[..length]))
+                .collect::<BTreeSet<&str>>();
+            for branch in branches {
+                lines.push(format!("    h{branch} [label=\"\", shape=point];"));
+            }
+            for code in codes {
+                let label = format!("{:#04x}\n{} times", code.byte_value, code.count);
+                lines.push(format!("    h{} [label={}];", code.codeword, dot_string(&label)));
+            }
+
+            let nodes = codes.iter().flat_map(|code| (1..=code.codeword.len()).map(|length| &code.codeword[..length]));
+            for node in nodes.collect::<BTreeSet<&str>>() {
+                let (parent, branch) = node.split_at(node.len() - 1);
+                lines.push(format!("    h{parent} -> h{node} [label=\"{branch}\"];"));
+            }
+            lines.push("  }".to_string());
+        }
+
+        lines.push("}".to_string());
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+}
+
+/// `text` as a JSON string literal
+pub fn json_string(text : &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            c if (c as u32) < 0x20 => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `text` as a DOT string, lines ending in `\n`
+fn dot_string(text : &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+
+#[cfg(test)]
+mod dump_test {
+    use crate::dump::*;
+
+    fn dump() -> DictionaryDump {
+        let entry = |codeword : IntType, phrase : &str, uses : usize| DictionaryEntry {
+            codeword,
+            width : 2,
+            phrase : phrase.chars().collect(),
+            uses,
+        };
+        DictionaryDump {
+            mode : Mode::Text,
+            entries : vec![entry(0, "a", 3), entry(1, "\"", 0), entry(2, "a\"a", 1)],
+            huffman : Some(vec![
+                HuffmanCode { byte_value : 0, count : 2, codeword : "0".to_string() },
+                HuffmanCode { byte_value : 7, count : 1, codeword : "1".to_string() },
+            ]),
+        }
+    }
+
+    #[test]
+    fn json_lists_entries_and_codes() {
+        assert_eq!(
+            dump().to_json(),
+            "{\n  \"mode\": \"text\",\n  \"entries\": [\n\
+            \x20   {\"codeword\": 0, \"width\": 2, \"phrase\": \"a\", \"uses\": 3},\n\
+            \x20   {\"codeword\": 1, \"width\": 2, \"phrase\": \"\\\"\", \"uses\": 0},\n\
+            \x20   {\"codeword\": 2, \"width\": 2, \"phrase\": \"a\\\"a\", \"uses\": 1}\n  ],\n  \"huffman\": [\n\
+            \x20   {\"byte\": 0, \"count\": 2, \"codeword\": \"0\"},\n\
+            \x20   {\"byte\": 7, \"count\": 1, \"codeword\": \"1\"}\n  ]\n}\n",
+        );
+        assert!(DictionaryDump { huffman : None, ..dump() }.to_json().ends_with("\"huffman\": null\n}\n"));
+    }
+
+    #[test]
+    fn dot_hangs_entries_off_their_longest_prefix() {
+        let dot = dump().to_dot();
+
+        assert!(dot.starts_with("digraph dictionary {\n"));
+        assert!(dot.contains("    lzw -> w0 [label=\"\\\"a\\\"\"];\n"));
+        assert!(dot.contains("    w0 -> w2 [label=\"\\\"\\\\\\\"a\\\"\"];\n"));
+        assert!(dot.contains("    w2 [label=\"2\\n\\\"a\\\\\\\"a\\\"\\n1 uses, 2 bits\"];\n"));
+        assert!(dot.contains("    h -> h1 [label=\"1\"];\n"));
+        assert!(dot.contains("    h0 [label=\"0x00\\n2 times\"];\n"));
+        assert!(!DictionaryDump { huffman : None, ..dump() }.to_dot().contains("cluster_huffman"));
+    }
+}
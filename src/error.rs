@@ -0,0 +1,64 @@
+use std::fmt;
+
+use crate::limits::Limit;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// Text mode was asked to encode input that is not UTF-8
+    InvalidUtf8,
+    /// Symbol with no codeword in the initial dictionary
+    UnknownSymbol(char),
+    /// Container header is present but can't be understood
+    BadHeader(&'static str),
+    /// Container was written by a newer format version
+    UnsupportedVersion(u8),
+    /// Preset dictionary can't be read, or doesn't suit the stream
+    BadDictionary(&'static str),
+    /// Stream was made with the preset dictionary of this ID, and none was given
+    MissingDictionary(u32),
+    /// Stream was made with a different preset dictionary than the one given
+    WrongDictionary { expected: u32, found: u32 },
+    /// Archive index or entry data can't be understood
+    BadArchive(&'static str),
+    /// Data decoded to something other than what was stored
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// Stream holds something no encoder writes
+    Corrupt(&'static str),
+    /// Data decoded to a different length than the header records
+    WrongSize { expected: u64, found: u64 },
+    /// Coding would go past one of the `Limits` set, `max` being its value
+    LimitExceeded { limit: Limit, max: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUtf8 => write!(f, "input is not valid UTF-8"),
+            Error::UnknownSymbol(c) => write!(f, "symbol {c:?} is not in the initial dictionary"),
+            Error::BadHeader(reason) => write!(f, "malformed header: {reason}"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            Error::BadDictionary(reason) => write!(f, "unusable preset dictionary: {reason}"),
+            Error::MissingDictionary(id) => write!(f, "needs preset dictionary {id:08x}"),
+            Error::WrongDictionary { expected, found } => {
+                write!(f, "needs preset dictionary {expected:08x}, got {found:08x}")
+            },
+            Error::BadArchive(reason) => write!(f, "malformed archive: {reason}"),
+            Error::ChecksumMismatch { expected, found } => {
+                write!(f, "checksum {found:08x} does not match the stored {expected:08x}")
+            },
+            Error::Corrupt(reason) => write!(f, "corrupt data: {reason}"),
+            Error::WrongSize { expected, found } => {
+                write!(f, "decoded to {found} bytes instead of {expected}")
+            },
+            Error::LimitExceeded { limit, max } => match limit {
+                Limit::Entries => write!(f, "dictionary would grow past the limit of {max} entries"),
+                Limit::Output => write!(f, "output would grow past the limit of {max} bytes"),
+                Limit::Memory => write!(f, "would need more than the limit of {max} bytes of memory"),
+                Limit::Work => write!(f, "dictionary would take more than the limit of {max} symbols to build"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,13 +1,81 @@
 use std::collections:: BTreeMap;
 
-use crate::{usize_to_u8, Bits};
+use crate::error::Error;
+use crate::limits::Limits;
+use crate::{usize_to_u8, BitReader, Bits};
+
+/// Whether the Huffman codec runs over the LZW output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HuffmanStage {
+    Off,
+    On,
+    /// Run it, but keep the result only if it came out smaller
+    IfSmaller,
+}
+
+impl HuffmanStage {
+    /// Stage for a compression level from 1 to 9, see `LzwConfig::level`.
+    /// 
+    /// The LZW output is close to evenly spread over byte values, so only
+    /// the slowest levels bother trying.
+    pub fn level(level : u8) -> Self {
+        if level >= 8 {
+            HuffmanStage::IfSmaller
+        } else {
+            HuffmanStage::Off
+        }
+    }
+}
+
+
+/// What the frequency table at the start of a Huffman stream says, read
+/// without decoding the rest
+#[derive(Debug, Clone, PartialEq)]
+pub struct HuffmanSummary {
+    /// Byte values with a codeword
+    pub symbols: usize,
+    /// Shortest and longest codeword in bits
+    pub shortest: usize,
+    pub longest: usize,
+    /// Bytes the table says were encoded
+    pub encoded_bytes: usize,
+    /// Codeword bits per encoded byte, on average
+    pub mean_bits: f64,
+}
+
+/// What `HuffmanEncoder::encode_with_stats` saw, to tell how close the
+/// stage gets to the best a code for single bytes can do
+#[derive(Debug, Clone, PartialEq)]
+pub struct HuffmanStats {
+    /// Byte value and the length of its codeword in bits, for each byte
+    /// value in the input, in byte order
+    pub code_lengths: Vec<(u8, usize)>,
+    pub encoded_bytes: usize,
+    /// Shannon entropy of the input in bits per byte, what the best such code would average
+    pub entropy: f64,
+    /// Codeword bits per byte actually written, frequency table left out
+    pub mean_bits: f64,
+}
 
+#[derive(Default)]
 pub struct HuffmanEncoder {
 
 }
 
+#[derive(Default)]
 pub struct HuffmanDecoder {
-    
+    /// Only `max_output` and `max_memory` apply, both to the decoded bytes
+    limits : Limits,
+}
+
+/// Where a byte value sits in the Huffman tree, as `HuffmanDecoder::tree` reads it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanCode {
+    pub byte_value: u8,
+    /// Its count in the frequency table
+    pub count: u16,
+    /// Branches taken from the root to the byte value, a '0' or '1' each
+    pub codeword: String,
 }
 
 pub struct HuffmanLeaf {
@@ -23,14 +91,19 @@ impl HuffmanEncoder {
     }
 
     pub fn encode(self, input : &[u8]) -> Vec<u8> {
-        let mut counts = [0;256];
+        self.encode_with_stats(input).0
+    }
+
+    /// Same as `encode`, also reporting the codeword lengths and how they
+    /// compare to the entropy of `input`
+    pub fn encode_with_stats(self, input : &[u8]) -> (Vec<u8>, HuffmanStats) {
+        let mut byte_counts = [0usize; 256];
 
         for b in input {
-            counts[*b as usize] += 1;
+            byte_counts[*b as usize] += 1;
         }
 
-        let transmitted_counts = counts.clone().map(|n| n as u16);
-        
+        let counts = table_counts(&byte_counts);
 
         let codeword_tree = construct_tree(&counts);
 
@@ -39,35 +112,107 @@ impl HuffmanEncoder {
             .map(|leaf| (leaf.byte_value, leaf.codeword))
             .collect::<BTreeMap<_,_>>();
 
+        let code_lengths = tree_map.iter().map(|(b, codeword)| (*b, codeword.size)).collect::<Vec<(u8, usize)>>();
+        let total = input.len().max(1) as f64;
+        let entropy = byte_counts
+            .iter()
+            .filter(|n| **n > 0)
+            .map(|n| {
+                let p = *n as f64 / total;
+                p * (1.0 / p).log2()
+            })
+            .sum::<f64>();
+        let bits = code_lengths.iter().map(|(b, length)| length * byte_counts[usize::from(*b)]).sum::<usize>();
+        let stats = HuffmanStats {
+            code_lengths,
+            encoded_bytes : input.len(),
+            entropy,
+            mean_bits : bits as f64 / total,
+        };
+
         let mut output = Bits::new(vec![], 0);
 
-        for b in input.into_iter() {
+        for b in input.iter() {
             let codeword = tree_map.get(b).expect("Byte value must exist in encoding tree").clone();
             output = output.concat(codeword);
         }
 
 
         // First 512 bytes are dedicated to character frequency
-        let mut transmission = Vec::from(bytemuck::cast_slice::<u16, u8>(&transmitted_counts));
+        let mut transmission = counts.iter().flat_map(|n : &u16| n.to_le_bytes()).collect::<Vec<u8>>();
 
         let output = usize_to_u8(&output.bits);
 
         transmission.extend(output);
 
-        transmission
+        (transmission, stats)
     }
 }
 
 impl HuffmanDecoder {
     pub fn new() -> Self {
-        HuffmanDecoder {}
+        HuffmanDecoder { limits : Limits::none() }
+    }
+
+    pub fn with_limits(limits : Limits) -> Self {
+        HuffmanDecoder { limits }
     }
 
-    pub fn decode(self, input : &[u8]) -> Vec<u8> {
-        let counts : &[u16] = bytemuck::cast_slice::<u8, u16>(&input[0..512]);
+    /// Reads the frequency table of a stream from `HuffmanEncoder::encode`,
+    /// `None` if it is cut short
+    pub fn summarize(input : &[u8]) -> Option<HuffmanSummary> {
+        let counts = read_counts(input)?;
+        let tree = construct_tree(&counts);
+
+        let encoded_bytes = counts.iter().map(|n| usize::from(*n)).sum::<usize>();
+        let bits = tree
+            .iter()
+            .map(|leaf| leaf.codeword.size * usize::from(counts[usize::from(leaf.byte_value)]))
+            .sum::<usize>();
+
+        Some(HuffmanSummary {
+            symbols : tree.len(),
+            shortest : tree.iter().map(|leaf| leaf.codeword.size).min().unwrap_or(0),
+            longest : tree.iter().map(|leaf| leaf.codeword.size).max().unwrap_or(0),
+            encoded_bytes,
+            mean_bits : bits as f64 / encoded_bytes.max(1) as f64,
+        })
+    }
+
+    /// The tree `construct_tree` builds from the frequency table of a stream
+    /// from `HuffmanEncoder::encode`, as a code per byte value it counts, in
+    /// byte order. `None` if the table is cut short.
+    pub fn tree(input : &[u8]) -> Option<Vec<HuffmanCode>> {
+        let counts = read_counts(input)?;
+        let mut codes = construct_tree(&counts)
+            .into_iter()
+            .map(|leaf| {
+                let size = leaf.codeword.size;
+                let bits = leaf.codeword.shift_left().bits;
+                let mut reader = BitReader::new(&bits);
+                HuffmanCode {
+                    byte_value : leaf.byte_value,
+                    count : counts[usize::from(leaf.byte_value)],
+                    codeword : (0..size).map(|_| if reader.read(1) == Some(1) { '1' } else { '0' }).collect(),
+                }
+            })
+            .collect::<Vec<HuffmanCode>>();
+        codes.sort_by_key(|code| code.byte_value);
+        Some(codes)
+    }
+
+    /// Fails with `Error::Corrupt` if the frequency table is cut short, and
+    /// with `Error::LimitExceeded` once the output is past the limits
+    pub fn decode(self, input : &[u8]) -> Result<Vec<u8>, Error> {
+        let counts = read_counts(input).ok_or(Error::Corrupt("Huffman frequency table cut short"))?;
         let input = &input[512..];
 
-        let codeword_tree = construct_tree(counts);
+        let codeword_tree = construct_tree(&counts);
+
+        // Nothing was encoded
+        if codeword_tree.is_empty() {
+            return Ok(vec![]);
+        }
 
         let min_word = codeword_tree.iter().map(|leaf| leaf.codeword.size).min().expect("tree must have leaves");
         let max_word = codeword_tree.iter().map(|leaf| leaf.codeword.size).max().expect("tree must have leaves");
@@ -95,9 +240,9 @@ impl HuffmanDecoder {
         while idx < input.len() && word_size <= max_word {
             let start_idx = idx;
             let end_idx = idx + (word_size+bit_idx).div_ceil(8);
-            let new_bit_idx = (bit_idx + word_size as usize) % 8;
+            let new_bit_idx = (bit_idx + word_size) % 8;
 
-            if input.len() <= end_idx {
+            if input.len() < end_idx {
                 break
             }
             let mut codeword = input[start_idx..=end_idx-1].to_vec();
@@ -124,6 +269,8 @@ impl HuffmanDecoder {
                 Some(byte) => {
                     word_size = min_word;
                     output.push(*byte);
+                    self.limits.check_output(output.len() as u64)?;
+                    self.limits.check_memory(output.len())?;
                 },
                 None => {
                     word_size+=1;
@@ -138,13 +285,48 @@ impl HuffmanDecoder {
             }
         }
 
-        output
+        Ok(output)
     }
 }
 
 
+/// Largest total the frequency table may add up to, so the weights of
+/// subtrees built from it fit in a u16
+const TABLE_TOTAL: usize = u16::MAX as usize;
+
+/// Counts for the frequency table. Inputs too long for it to hold exact
+/// counts get them scaled down, every byte present keeping a count of at
+/// least one, which costs a little compression but keeps the format.
+fn table_counts(byte_counts : &[usize; 256]) -> [u16; 256] {
+    let total = byte_counts.iter().sum::<usize>();
+    // Room for every byte value rounding up to one
+    let budget = TABLE_TOTAL - 256;
+
+    let mut counts = [0u16; 256];
+    for (count, n) in counts.iter_mut().zip(byte_counts) {
+        *count = if total <= TABLE_TOTAL {
+            *n as u16
+        } else if *n == 0 {
+            0
+        } else {
+            (n * budget / total).max(1) as u16
+        };
+    }
+    counts
+}
+
+/// Frequency table at the start of a stream, 256 little-endian u16s
+fn read_counts(input : &[u8]) -> Option<[u16; 256]> {
+    let table = input.get(0..512)?;
+    let mut counts = [0; 256];
+    for (count, bytes) in counts.iter_mut().zip(table.chunks_exact(2)) {
+        *count = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Some(counts)
+}
+
 fn construct_tree(character_frequency : &[u16]) -> Vec<HuffmanLeaf> {
-    let total = character_frequency.iter().sum();
+    let total = character_frequency.iter().map(|n| u32::from(*n)).sum::<u32>();
 
 
     let mut counts = character_frequency
@@ -166,7 +348,13 @@ fn construct_tree(character_frequency : &[u16]) -> Vec<HuffmanLeaf> {
         })
         .collect::<Vec<HuffmanLeaf>>();
 
-    while (tree[0].subtree_weight as u16) != total {
+    // A lone symbol still needs one bit, or its occurrences can't be counted
+    if tree.len() == 1 {
+        tree[0].codeword.push_from_left(0);
+        return tree;
+    }
+
+    while !tree.is_empty() && tree[0].subtree_weight != total {
         let mut min_id_weight = (tree[0].subtree_id, tree[0].subtree_weight);
         let mut min2_id_weight = (tree[0].subtree_id, u32::MAX);
 
@@ -209,3 +397,133 @@ fn construct_tree(character_frequency : &[u16]) -> Vec<HuffmanLeaf> {
 
     tree
 }
+
+
+#[cfg(test)]
+mod huffman_test {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::huffman::*;
+
+    #[test]
+    fn round_trip() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let encoded = HuffmanEncoder::new().encode(text);
+
+        // Padding in the last byte may decode to a few more bytes
+        assert!(encoded.len() < text.len());
+        assert!(HuffmanDecoder::new().decode(&encoded).unwrap().starts_with(text));
+    }
+
+    #[test]
+    fn long_inputs_scale_the_table() {
+        // More of one byte than a u16 counts, and rare ones that must keep a codeword
+        let mut input = vec![b'a'; 200_000];
+        input.extend(b"bcd".repeat(1000));
+        input.push(b'z');
+
+        let encoded = HuffmanEncoder::new().encode(&input);
+        assert!(HuffmanDecoder::new().decode(&encoded).unwrap().starts_with(&input));
+
+        let summary = HuffmanDecoder::summarize(&encoded).unwrap();
+        assert_eq!(summary.symbols, 5);
+        assert!(summary.encoded_bytes <= usize::from(u16::MAX));
+    }
+
+    #[test]
+    fn stats_compare_codewords_to_entropy() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let (encoded, stats) = HuffmanEncoder::new().encode_with_stats(text);
+        assert_eq!(encoded, HuffmanEncoder::new().encode(text));
+
+        let mut present = text.to_vec();
+        present.sort();
+        present.dedup();
+        assert_eq!(stats.code_lengths.iter().map(|(b, _)| *b).collect::<Vec<u8>>(), present);
+        assert_eq!(stats.encoded_bytes, text.len());
+
+        // Huffman codes average within a bit of the entropy
+        assert!(stats.entropy <= stats.mean_bits && stats.mean_bits < stats.entropy + 1.0, "{stats:?}");
+        // Up to a word of padding after the codewords
+        let bytes = (stats.mean_bits * text.len() as f64 / 8.0).ceil() as usize;
+        assert!((bytes..bytes + 8).contains(&(encoded.len() - 512)));
+
+        let (_, lone) = HuffmanEncoder::new().encode_with_stats(b"aaaa");
+        assert_eq!((lone.code_lengths, lone.entropy, lone.mean_bits), (vec![(b'a', 1)], 0.0, 1.0));
+    }
+
+    #[test]
+    fn tree_matches_the_encoder() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let (encoded, stats) = HuffmanEncoder::new().encode_with_stats(text);
+        let tree = HuffmanDecoder::tree(&encoded).unwrap();
+
+        let lengths = tree.iter().map(|code| (code.byte_value, code.codeword.len())).collect::<Vec<(u8, usize)>>();
+        assert_eq!(lengths, stats.code_lengths);
+        assert_eq!(tree.iter().map(|code| usize::from(code.count)).sum::<usize>(), text.len());
+
+        // No codeword starts another
+        for a in &tree {
+            assert!(a.codeword.chars().all(|bit| bit == '0' || bit == '1'));
+            assert!(tree.iter().all(|b| a.byte_value == b.byte_value || !b.codeword.starts_with(&a.codeword)));
+        }
+        assert!(HuffmanDecoder::tree(&encoded[..511]).is_none());
+    }
+
+    #[test]
+    fn limits_cap_the_output() {
+        let encoded = HuffmanEncoder::new().encode(&b"abcd".repeat(25_000));
+        let limits = Limits { max_output : Some(1000), ..Limits::none() };
+
+        assert!(matches!(HuffmanDecoder::with_limits(limits).decode(&encoded), Err(Error::LimitExceeded { .. })));
+        // Leaving room for what the padding decodes to
+        assert!(HuffmanDecoder::with_limits(Limits { max_output : Some(100_100), ..limits }).decode(&encoded).is_ok());
+    }
+
+    #[test]
+    fn hostile_tables_decode_or_fail_cleanly() {
+        for length in [0, 1, 511] {
+            assert!(matches!(HuffmanDecoder::new().decode(&vec![0xFF; length]), Err(Error::Corrupt(_))));
+        }
+
+        // Every byte equally likely, one byte alone, and counts doubling so
+        // the tree is as deep as the table allows
+        let mut doubling = [0u16; 256];
+        for (i, count) in doubling.iter_mut().take(16).enumerate() {
+            *count = 1 << i;
+        }
+        let mut lone = [0u16; 256];
+        lone[b'x' as usize] = 1;
+
+        for counts in [[u16::MAX; 256], lone, doubling] {
+            let table = counts.iter().flat_map(|n| n.to_le_bytes()).collect::<Vec<u8>>();
+            for data in [vec![], vec![0xFF; 9], (0..=u8::MAX).collect()] {
+                let decoded = HuffmanDecoder::new().decode(&[&table[..], &data[..]].concat()).unwrap();
+                // Every codeword is at least a bit
+                assert!(decoded.len() <= data.len() * 8);
+            }
+        }
+    }
+
+    /// Arbitrary bytes, a few bytes over and over, and bytes from a small
+    /// skewed set so codewords come out of different lengths
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            vec(any::<u8>(), 0..2000),
+            (vec(any::<u8>(), 1..6), 1..500usize).prop_map(|(pattern, times)| pattern.repeat(times)),
+            vec(prop_oneof![8 => Just(b'e'), 4 => Just(b't'), 2 => Just(b'a'), 1 => any::<u8>()], 0..2000),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn any_input_round_trips(input in bytes()) {
+            let decoded = HuffmanDecoder::new().decode(&HuffmanEncoder::new().encode(&input)).unwrap();
+
+            // Up to a word of padding, which decodes to a byte per bit at most
+            prop_assert_eq!(&decoded[..input.len()], &input[..]);
+            prop_assert!(decoded.len() - input.len() < 64);
+        }
+    }
+}
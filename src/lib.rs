@@ -0,0 +1,329 @@
+//! LZW compression with an optional Huffman stage, and the container format
+//! both are stored in.
+
+pub mod archive;
+pub mod checksum;
+pub mod container;
+pub mod dump;
+pub mod error;
+pub mod huffman;
+pub mod limits;
+pub mod lzw;
+pub mod preset;
+
+
+pub type IntType = usize;
+
+pub const INTTYPE_BITS : IntType = (0 as IntType).count_zeros() as IntType;
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Bits {
+    pub bits: Vec<IntType>,
+    pub size: IntType,
+}
+
+impl Bits {
+    pub fn new(val : Vec<IntType>, word_size : IntType) -> Self {
+        let mut bits = val;
+
+        let available_bits = bits.len() as IntType * INTTYPE_BITS;
+
+        if word_size > available_bits {
+            let pad_size = (word_size - available_bits) / INTTYPE_BITS;
+            let mut padded = vec![0; pad_size];
+
+            padded.append(&mut bits);
+            bits = padded;
+        }
+
+        Bits {
+            bits,
+            size : word_size,
+        }
+    }
+
+    /// Packed bits, cut after the last byte holding any of them
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = usize_to_u8(&self.bits);
+        bytes.truncate(self.size.div_ceil(8));
+        bytes
+    }
+
+    fn unused(&self) -> IntType {
+        self.bits.len() as IntType * INTTYPE_BITS - self.size
+    }
+
+    /// Concatenates two Bits into one Bits, with their combined size. 
+    /// 
+    /// Assumes self is left-adjusted, and other is right-adjusted.
+    pub fn concat(mut self, mut other : Bits) -> Bits {
+        let combined_size = self.size + other.size;
+
+        let remainder_shift = self.unused(); // Space left for other's bits
+        let filler_shift = INTTYPE_BITS - remainder_shift;
+
+        other = other.shift_left();
+
+        if self.unused() == 0 {
+            self.bits.append(&mut other.bits);
+            return Bits { bits : self.bits, size : combined_size }
+        }
+
+        // Shuffle other's bits backwards
+        for i in other.bits {
+            let end = self.bits.last_mut().unwrap();
+            let fill = i >> filler_shift;
+            let remainder = i << remainder_shift;
+
+            *end |= fill;
+            self.bits.push(remainder);
+        }
+
+        let mut new = Bits { bits : self.bits, size : combined_size };
+
+        while new.unused() >= INTTYPE_BITS {
+            new.bits.pop();
+        }
+        new
+    }
+
+    /// Shifts from the right to the left
+    fn shift_left(mut self) -> Bits {
+        let difference = self.unused();
+
+        // Already fills every word
+        if difference == 0 {
+            return self;
+        }
+    
+        let mut new_bits = vec![];
+    
+        self.bits.push(0);
+        for w in self.bits.windows(2) {
+            let mut a = w[0];
+            let mut b = w[1];
+    
+            a <<= difference;
+            b >>= INTTYPE_BITS - difference;
+            new_bits.push(a | b);
+        }
+    
+        Bits {
+            bits : new_bits,
+            size : self.size
+        }
+    }
+
+    // Push a single bit onto a right adjusted Bits
+    fn push_from_left(&mut self, value : IntType) {
+        if self.size.is_multiple_of(INTTYPE_BITS) {
+            let mut front_bit = vec![0];
+            std::mem::swap(&mut self.bits, &mut front_bit);
+            self.bits.extend(front_bit);
+        }
+
+        let shift = self.size % INTTYPE_BITS;
+        let mask = value << shift;
+        self.bits[0] |= mask;
+
+        self.size+=1;
+    }
+}
+
+
+/// Reads back bits in the order `Bits::concat` packs them, most significant first
+pub struct BitReader<'a> {
+    words: &'a [IntType],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(words : &'a [IntType]) -> Self {
+        BitReader { words, position : 0 }
+    }
+
+    /// Reads the next `width` bits as a right-adjusted number.
+    /// 
+    /// Returns None if fewer than `width` bits remain.
+    pub fn read(&mut self, width : IntType) -> Option<IntType> {
+        if width == 0 {
+            return Some(0);
+        }
+        if self.position + width > self.words.len() * INTTYPE_BITS {
+            return None;
+        }
+
+        let idx = self.position / INTTYPE_BITS;
+        let offset = self.position % INTTYPE_BITS;
+
+        let mut value = (self.words[idx] << offset) >> (INTTYPE_BITS - width);
+
+        // Remaining bits are at the front of the next word
+        if offset + width > INTTYPE_BITS {
+            let spill = offset + width - INTTYPE_BITS;
+            value |= self.words[idx+1] >> (INTTYPE_BITS - spill);
+        }
+
+        self.position += width;
+        Some(value)
+    }
+
+    /// Number of bits read so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bits left to read
+    pub fn remaining(&self) -> usize {
+        self.words.len() * INTTYPE_BITS - self.position
+    }
+}
+
+
+pub fn usize_to_u8(i : &[usize]) -> Vec<u8> {
+    i.iter()
+        .flat_map(
+            |i|
+                bytemuck::cast::<usize, [u8; 8]>(*i).into_iter().rev()
+            )
+        .collect::<Vec<u8>>()
+}
+
+
+pub fn u8_to_usize(mut i : Vec<u8>) -> Vec<usize> {
+    if i.is_empty() {
+        return vec![];
+    }
+
+    let ratio = INTTYPE_BITS / 8; 
+    let disalignment = (ratio - (i.len() % ratio)) % ratio;
+    i.extend(vec![0; disalignment]);
+
+    let (mut segment, mut remainder) = i.split_at(ratio);
+    let mut new_vec : Vec<u8> = vec![];
+    new_vec.extend(segment.iter().rev());
+
+    while !remainder.is_empty() {
+        (segment, remainder) = remainder.split_at(ratio);
+        new_vec.extend(segment.iter().rev());
+    }
+
+    bytemuck::cast_slice::<u8, usize>(&new_vec[..]).to_vec()
+}
+
+
+
+
+
+#[cfg(test)]
+mod bits_test {
+    use crate::{BitReader, Bits};
+
+    #[test]
+    fn concat_test() {
+        let input1 = Bits {
+            bits : vec![
+                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
+                ],
+                size : 1,
+            };
+        let input2 = Bits { bits : vec![1], size : 1};
+
+        let target = Bits {
+            bits : vec![
+                0b11000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
+                ],
+                size : 2,
+            };
+
+        assert_eq!(input1.concat(input2), target);
+
+        let input1 = Bits {
+            bits : vec![
+                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
+                ],
+                size : 1,
+            };
+        let input2 = Bits { bits : vec![
+            0b00000000_00000000_00000000_00000000_00000000_00000000_00000000_00000001,
+            0b00000000_00000000_00000000_00000000_00000000_00000000_00000000_00000001,
+        ], size : 65};
+
+        let target = Bits {
+            bits : vec![
+                0b11000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000,
+                0b01000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000,
+                ],
+                size : 66,
+            };
+
+        assert_eq!(input1.concat(input2), target);
+
+    }
+
+
+    #[test]
+    fn left_shift_test() {
+        let input = Bits { bits : vec![1], size : 1};
+        let target = Bits {
+            bits : vec![
+                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
+            ],
+            size : 1,
+        };
+    
+        assert_eq!(input.shift_left(), target);
+
+    }
+
+
+    #[test]
+    fn bit_reader_test() {
+        let input = Bits::new(vec![], 0)
+            .concat(Bits { bits : vec![0b101], size : 3 })
+            .concat(Bits { bits : vec![u32::MAX as usize], size : 64 })
+            .concat(Bits { bits : vec![0b10], size : 2 });
+
+        let mut reader = BitReader::new(&input.bits);
+
+        assert_eq!(reader.read(3), Some(0b101));
+        assert_eq!(reader.read(64), Some(u32::MAX as usize));
+        assert_eq!(reader.read(2), Some(0b10));
+        assert_eq!(reader.read(60), None);
+    }
+
+}
+
+
+
+
+
+
+#[cfg(target_arch="x86_64")]
+#[cfg(test)]
+mod casting_tests {
+    use crate::*;
+
+
+    #[test]
+    fn u8_to_usize_test() {
+        let inp : &[u8] = &[0b0000_0001, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_0111, 0b0000_0000, 0b0000_1111, 0b0000_0000];
+        let output = u8_to_usize(inp.to_vec());
+        let target  = &[0b0000_0001_0000_0000_0000_0011_0000_0000_0000_0111_0000_0000_0000_1111_0000_0000usize];
+
+        assert_eq!(*target, *output);
+    }
+
+    #[test]
+    fn usize_to_u8_test() {
+        let inp = [0b0000_0001_0000_0000_0000_0011_0000_0000_0000_0011_0000_0000_0000_1001_0000_0000usize];
+        let output = usize_to_u8(&inp);
+        let target : &[u8] = &[0b0000_0001, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_1001, 0b0000_0000];
+
+        assert_eq!(target, &output);
+    }
+
+
+
+}
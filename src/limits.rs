@@ -0,0 +1,88 @@
+use std::mem::size_of;
+
+use crate::error::Error;
+
+/// Bounds on what a coder may build up, to stop hostile or damaged input
+/// from making it exhaust memory. `None` leaves that measure unbounded, which
+/// is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Dictionary entries, the initial alphabet and reserved codewords included
+    pub max_entries: Option<usize>,
+    /// Bytes a decoder may produce. LZW decoders count symbols, which take
+    /// at least a byte each.
+    pub max_output: Option<u64>,
+    /// Bytes a coder's dictionary and output may take, as estimated by
+    /// `dictionary_memory`
+    pub max_memory: Option<usize>,
+    /// Symbols an LZW decoder may copy into new dictionary entries. Every
+    /// LZMW or LZAP entry repeats a whole match, so a stream can make this
+    /// far more than the output while evictions keep the dictionary small.
+    pub max_work: Option<u64>,
+}
+
+/// Which of the `Limits` was reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Entries,
+    Output,
+    Memory,
+    Work,
+}
+
+/// Rough cost in bytes of a dictionary entry beyond its symbols: its share
+/// of the map, the `Vec` holding the symbols and the codeword
+const ENTRY_OVERHEAD: usize = 64;
+
+impl Limits {
+    pub fn none() -> Self {
+        Limits::default()
+    }
+
+    pub fn check_entries(&self, entries : usize) -> Result<(), Error> {
+        check(Limit::Entries, entries as u64, self.max_entries.map(|max| max as u64))
+    }
+
+    pub fn check_output(&self, size : u64) -> Result<(), Error> {
+        check(Limit::Output, size, self.max_output)
+    }
+
+    pub fn check_memory(&self, bytes : usize) -> Result<(), Error> {
+        check(Limit::Memory, bytes as u64, self.max_memory.map(|max| max as u64))
+    }
+
+    pub fn check_work(&self, symbols : u64) -> Result<(), Error> {
+        check(Limit::Work, symbols, self.max_work)
+    }
+}
+
+fn check(limit : Limit, value : u64, max : Option<u64>) -> Result<(), Error> {
+    match max {
+        Some(max) if value > max => Err(Error::LimitExceeded { limit, max }),
+        _ => Ok(()),
+    }
+}
+
+/// Estimated bytes taken by `entries` dictionary entries holding `symbols`
+/// symbols between them
+pub fn dictionary_memory(entries : usize, symbols : usize) -> usize {
+    entries.saturating_mul(ENTRY_OVERHEAD).saturating_add(symbols.saturating_mul(size_of::<char>()))
+}
+
+
+#[cfg(test)]
+mod limits_test {
+    use crate::limits::*;
+
+    #[test]
+    fn only_set_limits_apply() {
+        let limits = Limits { max_output : Some(10), ..Limits::none() };
+
+        assert!(limits.check_output(10).is_ok());
+        assert!(matches!(limits.check_output(11), Err(Error::LimitExceeded { limit : Limit::Output, max : 10 })));
+        assert!(limits.check_entries(usize::MAX).is_ok());
+        assert!(limits.check_memory(usize::MAX).is_ok());
+        assert!(limits.check_work(u64::MAX).is_ok());
+        assert_eq!(dictionary_memory(usize::MAX, 1), usize::MAX);
+    }
+}
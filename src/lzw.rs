@@ -1,253 +1,1943 @@
-use std::char;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::Arc;
 
-use crate::{Bits, IntType, INTTYPE_BITS};
+use crate::error::Error;
+use crate::limits::{dictionary_memory, Limits};
+use crate::preset::PresetDictionary;
+use crate::{BitReader, Bits, IntType, INTTYPE_BITS};
 
+/// Lowercase Norwegian alphabet the codec was originally built around.
 const ALPHABET: &str = " abcdefghijklmnopqrstuvwxyzæøå";
 
+/// Digits, punctuation and whitespace that text mode accepts on top of `ALPHABET`.
+const TEXT_EXTRA: &str = "\n\r\t0123456789.,:;!?'\"-()[]{}/\\&%+*=<>@#$_|~^`";
+
+
+/// Decides what a symbol is, and therefore which symbols seed the dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every byte of the input is a symbol. Round-trips any file.
+    Bytes,
+    /// Every Unicode scalar value is a symbol. Input must be UTF-8 and case is kept.
+    /// Characters outside the alphabet are escaped.
+    Text,
+    /// Lowercase `ALPHABET` only, as written by versions without a container header.
+    Legacy,
+}
+
+impl Mode {
+    /// Symbols the dictionary is seeded with, in codeword order
+    pub fn alphabet(self) -> Vec<char> {
+        match self {
+            Mode::Bytes => (0..=u8::MAX).map(char::from).collect(),
+            Mode::Text => {
+                let mut alphabet = ALPHABET.chars().collect::<Vec<char>>();
+                let upper = ALPHABET
+                    .chars()
+                    .filter(|c| c.is_alphabetic())
+                    .flat_map(char::to_uppercase);
+                alphabet.extend(upper);
+                alphabet.extend(TEXT_EXTRA.chars());
+                alphabet
+            },
+            Mode::Legacy => ALPHABET.chars().collect(),
+        }
+    }
+
+    /// Splits raw input into the symbols this mode encodes
+    pub fn symbols(self, input : &[u8]) -> Result<Vec<char>, Error> {
+        match self {
+            // Bytes map onto U+0000..=U+00FF, so every byte is its own char
+            Mode::Bytes => Ok(input.iter().map(|b| char::from(*b)).collect()),
+            Mode::Text | Mode::Legacy => {
+                let text = std::str::from_utf8(input).map_err(|_| Error::InvalidUtf8)?;
+                Ok(text.chars().collect())
+            },
+        }
+    }
+
+    /// Whether `symbol` can appear in this mode's input
+    pub fn can_encode(self, symbol : char) -> bool {
+        match self {
+            Mode::Bytes => u32::from(symbol) <= 0xFF,
+            Mode::Text => true,
+            Mode::Legacy => ALPHABET.contains(symbol),
+        }
+    }
+
+    /// Whether symbols missing from the alphabet can be sent raw behind an escape codeword
+    pub fn has_escape(self) -> bool {
+        self == Mode::Text
+    }
+
+    /// Whether the END-OF-STREAM and CLEAR codewords are reserved.
+    /// Legacy streams just run until the input does, and never reset.
+    pub fn has_control_words(self) -> bool {
+        self != Mode::Legacy
+    }
+
+    /// Codewords taken before anything is learned: the alphabet, then the reserved ones
+    pub fn initial_words(self) -> usize {
+        let reserved = usize::from(self.has_escape()) + 2 * usize::from(self.has_control_words());
+        self.alphabet().len() + reserved
+    }
+
+    /// Inverse of `Mode::symbols`
+    pub fn to_bytes(self, symbols : &[char]) -> Vec<u8> {
+        match self {
+            Mode::Bytes => symbols.iter().map(|c| *c as u32 as u8).collect(),
+            Mode::Text | Mode::Legacy => symbols.iter().collect::<String>().into_bytes(),
+        }
+    }
+
+    /// How the command line and the exports call the mode
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Bytes => "bytes",
+            Mode::Text => "text",
+            Mode::Legacy => "legacy",
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            Mode::Bytes => 0,
+            Mode::Text => 1,
+            Mode::Legacy => 2,
+        }
+    }
+
+    pub fn from_id(id : u8) -> Option<Mode> {
+        match id {
+            0 => Some(Mode::Bytes),
+            1 => Some(Mode::Text),
+            2 => Some(Mode::Legacy),
+            _ => None,
+        }
+    }
+}
+
+
+/// What the encoder does once every codeword up to `max_word_size` bits is taken.
+/// 
+/// Apart from `Lru` the decoder follows along by itself, it only has to act
+/// on CLEAR codewords.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Keep coding with the dictionary as it is
+    Freeze,
+    /// Emit CLEAR and start over from the initial dictionary
+    Clear,
+    /// Keep coding with the full dictionary, like `Freeze`, but track the
+    /// compression ratio since the last reset as `compress` does. Emit CLEAR
+    /// once it falls more than `threshold` percent below the best seen.
+    AdaptiveClear { threshold: u8 },
+    /// Evict the least recently used entry that no other entry extends,
+    /// and give its codeword to the new entry (LZT). Decoder must know.
+    Lru,
+}
+
+impl FullPolicy {
+    pub fn id(self) -> u8 {
+        match self {
+            FullPolicy::Freeze => 0,
+            FullPolicy::Clear => 1,
+            FullPolicy::AdaptiveClear { .. } => 2,
+            FullPolicy::Lru => 3,
+        }
+    }
+
+    /// Setting that goes with the policy, if any
+    pub fn parameter(self) -> u8 {
+        match self {
+            FullPolicy::AdaptiveClear { threshold } => threshold,
+            _ => 0,
+        }
+    }
+
+    pub fn from_id(id : u8, parameter : u8) -> Option<FullPolicy> {
+        match id {
+            0 => Some(FullPolicy::Freeze),
+            1 => Some(FullPolicy::Clear),
+            2 => Some(FullPolicy::AdaptiveClear { threshold : parameter }),
+            3 => Some(FullPolicy::Lru),
+            _ => None,
+        }
+    }
+}
+
+
+/// How the encoder splits its input into dictionary entries.
+/// 
+/// Any split decodes the same way, so the decoder doesn't need to know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parsing {
+    /// Always the longest entry
+    Greedy,
+    /// The entry that lets the match after it reach furthest, which tends to
+    /// need fewer codewords (flexible parsing). Slower to encode.
+    Flexible,
+}
+
+
+/// Which entries the dictionary gains from each match.
+/// 
+/// All of them build on the previous match, and differ in how much of the
+/// current match they append to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Growth {
+    /// Previous match plus the first symbol of the current one. Plain LZW.
+    Classic,
+    /// Previous match plus the whole current match (LZMW)
+    Lzmw,
+    /// Previous match plus every prefix of the current match (LZAP)
+    Lzap,
+}
+
+impl Growth {
+    /// Lengths of the prefixes of a `current_length` symbol match that are
+    /// appended to the previous match, one new entry each
+    fn extensions(self, current_length : usize) -> std::ops::RangeInclusive<usize> {
+        match self {
+            Growth::Classic => 1..=1,
+            Growth::Lzmw => current_length..=current_length,
+            Growth::Lzap => 1..=current_length,
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            Growth::Classic => 0,
+            Growth::Lzmw => 1,
+            Growth::Lzap => 2,
+        }
+    }
+
+    pub fn from_id(id : u8) -> Option<Growth> {
+        match id {
+            0 => Some(Growth::Classic),
+            1 => Some(Growth::Lzmw),
+            2 => Some(Growth::Lzap),
+            _ => None,
+        }
+    }
+}
+
+
+/// Bookkeeping for `FullPolicy::Lru`.
+/// 
+/// Tracks every entry made by extending another one: what it extends, how
+/// many entries extend it in turn and when it was last used. Only entries
+/// nothing extends are evicted, so every prefix of an entry stays in the
+/// dictionary and greedy matching keeps working.
+#[derive(Default)]
+struct Recency {
+    clock: u64,
+    entries: BTreeMap<IntType, RecencyEntry>,
+    /// (last used, codeword) of every tracked entry nothing extends
+    leaves: BTreeSet<(u64, IntType)>,
+}
+
+struct RecencyEntry {
+    parent: IntType,
+    children: usize,
+    last_used: u64,
+}
+
+impl Recency {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Marks `codeword` as just used
+    fn touch(&mut self, codeword : IntType) {
+        let now = self.tick();
+
+        if let Some(entry) = self.entries.get_mut(&codeword) {
+            if entry.children == 0 {
+                self.leaves.remove(&(entry.last_used, codeword));
+                self.leaves.insert((now, codeword));
+            }
+            entry.last_used = now;
+        }
+    }
+
+    /// Starts tracking `codeword`, an entry extending `parent`
+    fn add(&mut self, codeword : IntType, parent : IntType) {
+        let now = self.tick();
+
+        if let Some(parent_entry) = self.entries.get_mut(&parent) {
+            if parent_entry.children == 0 {
+                self.leaves.remove(&(parent_entry.last_used, parent));
+            }
+            parent_entry.children += 1;
+        }
+
+        self.entries.insert(codeword, RecencyEntry { parent, children : 0, last_used : now });
+        self.leaves.insert((now, codeword));
+    }
+
+    /// Least recently used entry nothing extends, passing over `keep`
+    fn least_recent_leaf(&self, keep : Option<IntType>) -> Option<IntType> {
+        self.leaves
+            .iter()
+            .map(|(_, codeword)| *codeword)
+            .find(|codeword| Some(*codeword) != keep)
+    }
+
+    /// Stops tracking `codeword`, which must be a leaf
+    fn remove(&mut self, codeword : IntType) {
+        let Some(entry) = self.entries.remove(&codeword) else {
+            return
+        };
+        self.leaves.remove(&(entry.last_used, codeword));
+
+        if let Some(parent_entry) = self.entries.get_mut(&entry.parent) {
+            parent_entry.children -= 1;
+            if parent_entry.children == 0 {
+                self.leaves.insert((parent_entry.last_used, entry.parent));
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LzwConfig {
+    pub mode: Mode,
+    /// Codewords never grow wider than this. Raised to fit the initial dictionary if needed.
+    pub max_word_size: IntType,
+    /// What happens once all of those codewords are taken
+    pub full_policy: FullPolicy,
+    pub growth: Growth,
+    pub parsing: Parsing,
+    /// Phrases to seed the dictionary with. Must be made for `mode`.
+    pub preset: Option<Arc<PresetDictionary>>,
+    /// Bounds on the dictionary and, when decoding, the output. Not part of
+    /// the format, so the encoder and decoder needn't agree on them.
+    pub limits: Limits,
+}
+
+impl LzwConfig {
+    pub fn new(mode : Mode) -> Self {
+        LzwConfig {
+            mode,
+            max_word_size : 16,
+            full_policy : FullPolicy::AdaptiveClear { threshold : 0 },
+            growth : Growth::Classic,
+            parsing : Parsing::Greedy,
+            preset : None,
+            limits : Limits::none(),
+        }
+    }
+
+    /// Settings for a gzip-style compression level, from 1 (fastest) to 9
+    /// (smallest output). Level 6 is the default.
+    /// 
+    /// Higher levels allow wider codewords, use the full dictionary better
+    /// and parse more carefully. Growth and preset are left as in `new`.
+    pub fn level(mode : Mode, level : u8) -> Self {
+        let adaptive = FullPolicy::AdaptiveClear { threshold : 0 };
+
+        let (max_word_size, full_policy, parsing) = match level.clamp(1, 9) {
+            1 => (12, FullPolicy::Clear, Parsing::Greedy),
+            2 => (13, FullPolicy::Clear, Parsing::Greedy),
+            3 => (14, FullPolicy::Clear, Parsing::Greedy),
+            4 => (14, adaptive, Parsing::Greedy),
+            5 => (15, adaptive, Parsing::Greedy),
+            6 => (16, adaptive, Parsing::Greedy),
+            7 => (18, adaptive, Parsing::Flexible),
+            8 => (20, FullPolicy::Lru, Parsing::Flexible),
+            _ => (24, FullPolicy::Lru, Parsing::Flexible),
+        };
+
+        LzwConfig { max_word_size, full_policy, parsing, ..LzwConfig::new(mode) }
+    }
+
+    /// Phrases a preset dictionary can hold without growing the codewords
+    /// past `max_word_size`
+    pub fn preset_capacity(&self) -> usize {
+        word_limit(self.max_word_size).saturating_sub(self.mode.initial_words())
+    }
+}
+
+
+/// What `LzwEncoder::encode_with_stats` saw, for tuning the configuration
+/// to a kind of data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LzwStats {
+    /// Width -> number of codewords written that wide, control words included
+    pub widths: Vec<usize>,
+    /// Length -> number of matches written that long, in symbols
+    pub match_lengths: Vec<usize>,
+    /// Symbols sent raw after the escape codeword
+    pub escapes: usize,
+    /// Symbols read and codewords taken at that point, sampled as the input
+    /// goes by. Taken codewords include the alphabet and control words.
+    pub fill: Vec<(usize, usize)>,
+    /// Codewords the dictionary has room for
+    pub capacity: usize,
+    /// Symbols read when the encoder cleared the dictionary
+    pub resets: Vec<usize>,
+}
+
+impl LzwStats {
+    /// Number of codewords written
+    pub fn codes(&self) -> usize {
+        self.widths.iter().sum()
+    }
+
+    fn count(histogram : &mut Vec<usize>, value : usize) {
+        if histogram.len() <= value {
+            histogram.resize(value + 1, 0);
+        }
+        histogram[value] += 1;
+    }
+}
+
+/// Dictionary fill samples `LzwEncoder::encode_with_stats` takes over the input
+const FILL_SAMPLES: usize = 64;
+
+/// An entry of the dictionary `LzwDecoder::decode_with_dictionary` ends with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictionaryEntry {
+    pub codeword: IntType,
+    /// Width of the codewords once the entry was made
+    pub width: IntType,
+    pub phrase: Vec<char>,
+    /// Times the codeword was read since the entry was made
+    pub uses: usize,
+}
+
 
 pub struct LzwEncoder {
-    dict: BTreeMap<Vec<char>, Vec<IntType>>,
+    config: LzwConfig,
+    dict: BTreeMap<Vec<char>, IntType>,
     word_size: IntType,
-    next_word: Vec<IntType>,
+    next_word: IntType,
+    /// Announces a symbol missing from the dictionary, sent raw right after
+    escape: Option<IntType>,
+    /// Written after the last codeword
+    end_of_stream: Option<IntType>,
+    /// Tells the decoder to start over from the initial dictionary
+    clear: Option<IntType>,
+    /// Codeword -> sequence, only kept under `FullPolicy::Lru` to find what to evict
+    phrases: BTreeMap<IntType, Vec<char>>,
+    recency: Recency,
+    /// Length -> number of entries that long, to find matches when the
+    /// dictionary doesn't hold every prefix of its entries
+    lengths: BTreeMap<usize, usize>,
+    /// Symbols held by `dict` and `phrases`, for `Limits::max_memory`
+    stored_symbols: usize,
+
+    // Progress since the last reset, for `FullPolicy::AdaptiveClear`
+    symbols_since_reset: usize,
+    bits_at_reset: IntType,
+    next_ratio_check: usize,
+    best_ratio: f64,
+
+    /// Only collected by `encode_with_stats`
+    stats: Option<LzwStats>,
 }
 
 pub struct LzwDecoder {
-    dict: BTreeMap<Vec<IntType>, Vec<char>>,
+    config: LzwConfig,
+    dict: BTreeMap<IntType, Vec<char>>,
     word_size: IntType,
-    next_word: Vec<IntType>,
+    next_word: IntType,
+    escape: Option<IntType>,
+    end_of_stream: Option<IntType>,
+    clear: Option<IntType>,
+    recency: Recency,
+    /// Sequence -> codeword, only kept when growth isn't `Growth::Classic`,
+    /// which may come across the same entry twice
+    codes: BTreeMap<Vec<char>, IntType>,
+    /// Width -> number of codewords read that wide
+    widths: Vec<usize>,
+    /// Symbols held by `dict` and `codes`, for `Limits::max_memory`
+    stored_symbols: usize,
+    /// Symbols copied into new entries so far, for `Limits::max_work`
+    work: u64,
+    /// Codeword -> width once its entry was made and times read since,
+    /// only kept by `decode_with_dictionary`
+    usage: Option<BTreeMap<IntType, (IntType, usize)>>,
+}
+
+
+/// Width of a symbol sent after the escape codeword, enough for any `char`
+const RAW_SYMBOL_BITS: IntType = 21;
+
+/// How much further than the longest match a shorter one must let the next
+/// match reach before flexible parsing takes it under `Growth::Classic`
+const CLASSIC_DETOUR_MARGIN: usize = 2;
+
+/// Symbols read between compression ratio checks under `FullPolicy::AdaptiveClear`
+const RATIO_CHECK_INTERVAL: usize = 1000;
+
+/// Number of bits needed to write `codeword`
+fn word_size(codeword : IntType) -> IntType {
+    (codeword.checked_ilog2().unwrap_or(0) + 1) as IntType
 }
 
+/// One past the largest codeword that fits in `max_word_size` bits
+fn word_limit(max_word_size : IntType) -> IntType {
+    if max_word_size >= INTTYPE_BITS {
+        IntType::MAX
+    } else {
+        1 << max_word_size
+    }
+}
 
 
 impl LzwEncoder {
-    pub fn new() -> Self {
-        let dict = BTreeMap::<Vec<char>, Vec<IntType>>::new();
+    pub fn new(config : LzwConfig) -> Self {
+        let dict = BTreeMap::<Vec<char>, IntType>::new();
 
-        let next_word = vec![0];
+        let mut encoder = LzwEncoder {
+            config,
+            dict,
+            word_size : 0,
+            next_word : 0,
+            escape : None,
+            end_of_stream : None,
+            clear : None,
+            phrases : BTreeMap::new(),
+            recency : Recency::default(),
+            lengths : BTreeMap::new(),
+            stored_symbols : 0,
+            symbols_since_reset : 0,
+            bits_at_reset : 0,
+            next_ratio_check : 0,
+            best_ratio : 0.0,
+            stats : None,
+        };
 
-        let mut encoder = LzwEncoder { dict, word_size : 0, next_word };
-        
-        for c in ALPHABET.chars() {
-            encoder.insert(vec![c]);
+        encoder.reset(0);
+        encoder
+    }
+
+    /// Brings the dictionary back to the alphabet and the reserved codewords
+    fn reset(&mut self, bits_written : IntType) {
+        self.dict.clear();
+        self.phrases.clear();
+        self.recency = Recency::default();
+        self.next_word = 0;
+
+        let mode = self.config.mode;
+
+        for c in mode.alphabet() {
+            let codeword = self.next_word();
+            self.dict.insert(vec![c], codeword);
         }
+        self.lengths = BTreeMap::from([(1, self.dict.len())]);
+        self.stored_symbols = self.dict.len();
 
-        encoder
+        if mode.has_escape() {
+            self.escape = Some(self.next_word());
+        }
+        if mode.has_control_words() {
+            self.end_of_stream = Some(self.next_word());
+            self.clear = Some(self.next_word());
+        }
+
+        self.seed_preset();
+
+        self.symbols_since_reset = 0;
+        self.bits_at_reset = bits_written;
+        self.next_ratio_check = RATIO_CHECK_INTERVAL;
+        self.best_ratio = 0.0;
+    }
+
+    fn has_room(&self) -> bool {
+        self.next_word < word_limit(self.config.max_word_size)
+    }
+
+    /// Adds the preset phrases after the reserved codewords, making room
+    /// for them. The initial dictionary always fits.
+    fn seed_preset(&mut self) {
+        let preset = self.config.preset.clone();
+        let preset_len = preset.as_ref().map_or(0, |preset| preset.len());
+
+        let largest_word = self.next_word - 1 + preset_len;
+        self.config.max_word_size = self.config.max_word_size.max(word_size(largest_word));
+
+        if let Some(preset) = preset {
+            debug_assert_eq!(preset.mode(), self.config.mode);
+            for phrase in preset.phrases() {
+                self.insert(phrase.clone(), None);
+            }
+        }
+    }
+
+    /// Codeword a new entry extending `parent` would get, if it can be made
+    fn upcoming_word(&self, parent : Option<IntType>) -> Option<IntType> {
+        if self.has_room() {
+            Some(self.next_word)
+        } else if self.config.full_policy == FullPolicy::Lru {
+            self.recency.least_recent_leaf(parent)
+        } else {
+            None
+        }
     }
 
     /// Attempt to insert a new sequence of characters into the dictionary with a new codeword.
     /// 
-    /// Returns false if the sequence already has a codeword.
-    fn insert(&mut self, sequence : Vec<char>) -> bool {
-        if self.dict.contains_key(&sequence) {
+    /// `parent` is the codeword of the sequence minus its last symbol, if it has one.
+    /// Returns false if the sequence already has a codeword, or the dictionary is full.
+    /// 
+    /// Under `Growth::Classic` a sequence that already has a codeword takes
+    /// up another one anyway, since the decoder doesn't check. That only
+    /// happens when parsing isn't greedy.
+    fn insert(&mut self, sequence : Vec<char>, parent : Option<IntType>) -> bool {
+        let duplicate = self.dict.contains_key(&sequence);
+        if duplicate && self.config.growth != Growth::Classic {
             return false;
         }
-        
-        let codeword = self.next_word();
+        let Some(codeword) = self.upcoming_word(parent) else {
+            return false;
+        };
+
+        if self.has_room() {
+            self.next_word();
+        } else {
+            // Evict the entry whose codeword we're taking over
+            self.recency.remove(codeword);
+            let evicted = self.phrases.remove(&codeword).expect("evicted entry must exist");
+            self.stored_symbols -= evicted.len();
+
+            // A duplicate's sequence still belongs to the first codeword
+            if self.dict.get(&evicted) == Some(&codeword) {
+                self.dict.remove(&evicted);
+                self.forget_length(evicted.len());
+                self.stored_symbols -= evicted.len();
+            }
+        }
+
+        if self.config.full_policy == FullPolicy::Lru {
+            self.stored_symbols += sequence.len();
+            self.phrases.insert(codeword, sequence.clone());
+            if let Some(parent) = parent {
+                self.recency.add(codeword, parent);
+            }
+        }
+
+        if duplicate {
+            return true;
+        }
 
+        *self.lengths.entry(sequence.len()).or_default() += 1;
+        self.stored_symbols += sequence.len();
         self.dict.insert(sequence, codeword);
 
-        self.word_size = ((self.dict.len()-1).checked_ilog2().unwrap_or(0) + 1) as IntType;
+        true
+    }
 
-        return true
+    fn forget_length(&mut self, length : usize) {
+        if let Some(count) = self.lengths.get_mut(&length) {
+            *count -= 1;
+            if *count == 0 {
+                self.lengths.remove(&length);
+            }
+        }
+    }
+
+    /// Length of the longest entry `input` starts with, zero if none
+    fn longest_match(&self, input : &[char]) -> usize {
+        if self.config.growth == Growth::Classic {
+            // Every prefix of an entry is an entry too
+            let mut length = 0;
+            while length < input.len() && self.dict.contains_key(&input[..length+1]) {
+                length += 1;
+            }
+            return length;
+        }
+
+        // The greatest entry up to the input is either the longest entry it
+        // starts with, or shares a shorter start with it than the entry does
+        let longest_entry = self.lengths.keys().next_back().copied().unwrap_or(0);
+        let mut probe = &input[..input.len().min(longest_entry)];
+
+        while !probe.is_empty() {
+            let up_to_probe = (Bound::Unbounded, Bound::Included(probe));
+            let Some((entry, _)) = self.dict.range::<[char], _>(up_to_probe).next_back() else {
+                break
+            };
+
+            let common = entry.iter().zip(probe).take_while(|(a, b)| a == b).count();
+            if common == entry.len() {
+                return common;
+            }
+            probe = &probe[..common];
+        }
+
+        0
+    }
+
+    /// Length of the entry to write for the start of `input`, which must
+    /// begin with a symbol in the dictionary
+    fn choose_match(&self, input : &[char]) -> usize {
+        let longest = self.longest_match(input);
+
+        match self.config.parsing {
+            Parsing::Greedy => longest,
+            Parsing::Flexible => {
+                let reach = |length : usize| length + self.longest_match(&input[length..]);
+
+                // Without `Growth::Classic` not every prefix of an entry is one.
+                // Ties go to the longer match.
+                let furthest = (1..=longest)
+                    .filter(|length| self.dict.contains_key(&input[..*length]))
+                    .max_by_key(|length| reach(*length))
+                    .unwrap_or(longest);
+
+                // A shorter match costs the classic decoder a duplicate entry
+                // instead of a new one, so it has to win by more
+                let margin = match self.config.growth {
+                    Growth::Classic => CLASSIC_DETOUR_MARGIN,
+                    Growth::Lzmw | Growth::Lzap => 0,
+                };
+
+                if reach(furthest) > reach(longest) + margin {
+                    furthest
+                } else {
+                    longest
+                }
+            },
+        }
+    }
+
+    /// Makes the entries `growth` derives from two consecutive matches
+    fn grow(&mut self, previous : &[char], previous_word : IntType, current : &[char]) {
+        for length in self.config.growth.extensions(current.len()) {
+            // Once full, with nothing to evict, every entry would be refused
+            if self.upcoming_word(Some(previous_word)).is_none() {
+                break
+            }
+            let mut sequence = previous.to_vec();
+            sequence.extend(&current[..length]);
+            self.insert(sequence, Some(previous_word));
+        }
     }
 
     /// Each new sequence of letters that is discovered
     /// must have a different codeword. This function
     /// returns an unused codeword, and readies the
     /// next one.
-    fn next_word(&mut self) -> Vec<IntType> {
-        let old = self.next_word.clone();
+    fn next_word(&mut self) -> IntType {
+        let word = self.next_word;
+        self.next_word += 1;
 
-        for n in self.next_word.iter_mut().rev() {
-            *n+=1;
-            if *n != 0 {
-                break
+        // Every codeword handed out so far must be writable
+        self.word_size = word_size(word);
+
+        word
+    }
+
+    /// Decides whether a full dictionary should be cleared, given
+    /// how many bits have been written in total
+    fn reset_due(&mut self, bits_written : IntType) -> bool {
+        match self.config.full_policy {
+            // Never full, old entries make room for new ones
+            FullPolicy::Freeze | FullPolicy::Lru => false,
+            FullPolicy::Clear => true,
+            FullPolicy::AdaptiveClear { threshold } => {
+                if self.symbols_since_reset < self.next_ratio_check {
+                    return false;
+                }
+                self.next_ratio_check = self.symbols_since_reset + RATIO_CHECK_INTERVAL;
+
+                let bits = (bits_written - self.bits_at_reset).max(1);
+                let ratio = self.symbols_since_reset as f64 / bits as f64;
+
+                if ratio > self.best_ratio {
+                    self.best_ratio = ratio;
+                    return false;
+                }
+
+                ratio < self.best_ratio * (1.0 - f64::from(threshold) / 100.0)
+            },
+        }
+    }
+
+    /// Fails once the dictionary, or it and the output so far, are past `config.limits`
+    fn check_limits(&self, output : &Bits) -> Result<(), Error> {
+        let limits = &self.config.limits;
+        limits.check_entries(self.dict.len())?;
+        limits.check_memory(
+            dictionary_memory(self.dict.len() + self.phrases.len(), self.stored_symbols) + output.size / 8,
+        )
+    }
+
+    /// Appends `codeword` to `output`, `width` bits wide
+    fn write(&mut self, output : Bits, codeword : IntType, width : IntType) -> Bits {
+        if let Some(stats) = &mut self.stats {
+            LzwStats::count(&mut stats.widths, width);
+        }
+        output.concat(Bits::new(vec![codeword], width))
+    }
+
+    /// `write` for the codeword of a match `length` symbols long
+    fn write_match(&mut self, output : Bits, codeword : IntType, width : IntType, length : usize) -> Bits {
+        if let Some(stats) = &mut self.stats {
+            LzwStats::count(&mut stats.match_lengths, length);
+        }
+        self.write(output, codeword, width)
+    }
+
+    /// Appends the escape codeword and `symbol` raw after it
+    fn write_escaped(&mut self, output : Bits, symbol : char) -> Result<Bits, Error> {
+        let escape = self.escape.ok_or(Error::UnknownSymbol(symbol))?;
+        if let Some(stats) = &mut self.stats {
+            stats.escapes += 1;
+        }
+        let output = self.write(output, escape, self.word_size);
+        Ok(output.concat(Bits::new(vec![symbol as IntType], RAW_SYMBOL_BITS)))
+    }
+
+    /// Samples how many codewords are taken after `position` symbols,
+    /// unless the last sample is fewer than `interval` symbols back
+    fn sample_fill(&mut self, position : usize, interval : usize) {
+        let taken = self.next_word;
+        if let Some(stats) = &mut self.stats {
+            if stats.fill.last().is_none_or(|(at, _)| position >= at + interval.max(1)) {
+                stats.fill.push((position, taken));
             }
         }
+    }
 
-        if self.next_word[0] == 0 {
-            self.next_word.push(0);
-            self.next_word[0]+=1;
+    /// Writes `clear` and starts over from the initial dictionary, after
+    /// `position` symbols
+    fn clear_dictionary(&mut self, output : Bits, clear : IntType, position : usize) -> Bits {
+        let output = self.write(output, clear, self.word_size);
+        let full = self.next_word;
+        self.reset(output.size);
+
+        let emptied = self.next_word;
+        if let Some(stats) = &mut self.stats {
+            stats.fill.extend([(position, full), (position, emptied)]);
+            stats.resets.push(position);
         }
-        return old
+        output
     }
 
     /// Performs LZW compression
-    pub fn encode(mut self, input : Vec<char>) -> Bits {
+    /// 
+    /// Symbols outside the initial dictionary are escaped, which fails
+    /// only in `Mode::Legacy` since it has no escape codeword. Fails with
+    /// `Error::LimitExceeded` if the dictionary outgrows `config.limits`.
+    pub fn encode(mut self, input : Vec<char>) -> Result<Bits, Error> {
+        self.encode_symbols(input)
+    }
+
+    /// Same as `encode`, also collecting statistics on what was written
+    pub fn encode_with_stats(mut self, input : Vec<char>) -> Result<(Bits, LzwStats), Error> {
+        self.stats = Some(LzwStats { capacity : word_limit(self.config.max_word_size), ..LzwStats::default() });
+        let output = self.encode_symbols(input)?;
+        Ok((output, self.stats.take().unwrap_or_default()))
+    }
 
+    fn encode_symbols(&mut self, input : Vec<char>) -> Result<Bits, Error> {
+        if self.config.growth == Growth::Classic {
+            self.encode_classic(input)
+        } else {
+            self.encode_matches(input)
+        }
+    }
+
+    fn encode_classic(&mut self, input : Vec<char>) -> Result<Bits, Error> {
+        let fill_interval = input.len() / FILL_SAMPLES;
         let mut output : Bits = Bits { bits : vec![], size : 0 };
-        let mut sequence: Vec<char> = vec![];
+        let mut end_word_size = None;
+        let mut position = 0;
+
+        while position < input.len() {
+            self.check_limits(&output)?;
+            self.sample_fill(position, fill_interval);
+            let symbol = input[position];
+
+            if !self.dict.contains_key(&[symbol][..]) {
+                output = self.write_escaped(output, symbol)?;
+
+                // From now on the symbol has a codeword like any other.
+                // Like the initial alphabet, it is never evicted.
+                self.insert(vec![symbol], None);
+
+                position += 1;
+                self.symbols_since_reset += 1;
+                continue
+            }
+
+            let length = self.choose_match(&input[position..]);
+            let mut sequence = input[position..position+length].to_vec();
+            let codeword = self.dict[&sequence];
+            self.recency.touch(codeword);
+
+            position += length;
+            self.symbols_since_reset += length;
 
-        
-        for i in 0..input.len() {
             // Insertion into dict changes word_size before appending to output
             let current_word_size = self.word_size;
 
-            // Add next symbol to evaluated sequence
-            sequence.push(input[i]);    
+            let Some(&next) = input.get(position) else {
+                output = self.write_match(output, codeword, current_word_size, length);
 
-            if self.insert(sequence.clone()) {
-                // Insert succeeded. We've found a new sequence
+                // After that codeword the decoder expects one more entry than we
+                // made, unless there is no room for it
+                if self.has_room() {
+                    end_word_size = Some(word_size(self.next_word));
+                }
+                break
+            };
 
-                // Remove latest symbol
-                sequence.pop();
+            // The decoder makes this entry once it knows the next symbol,
+            // from the next codeword or escape
+            sequence.push(next);
+            let inserted = self.insert(sequence, Some(codeword));
 
-                if sequence.len() == 0 {
-                    panic!("Alphabet not comprehensive");
+            // Zip bits together tightly
+            output = self.write_match(output, codeword, current_word_size, length);
+
+            if !inserted && self.reset_due(output.size) {
+                if let Some(clear) = self.clear {
+                    output = self.clear_dictionary(output, clear, position);
                 }
+            }
+        }
+
+        if let Some(end_of_stream) = self.end_of_stream {
+            let end_word_size = end_word_size.unwrap_or(self.word_size);
+            output = self.write(output, end_of_stream, end_word_size);
+        }
+        self.sample_fill(input.len(), 1);
+
+        Ok(output)
+    }
 
-                // Get previously existing codeword, not the most recently created
-                let codeword = self.dict.get(&sequence).expect("encoding should exist").clone();
+    /// `encode` for growth strategies that wait for the whole current match
+    /// before adding entries.
+    /// 
+    /// The decoder then always knows every codeword it reads, so there is no
+    /// cScSc case and both sides agree on the width without looking ahead.
+    fn encode_matches(&mut self, input : Vec<char>) -> Result<Bits, Error> {
+        let fill_interval = input.len() / FILL_SAMPLES;
+        let mut output : Bits = Bits { bits : vec![], size : 0 };
+        // Last match and its codeword
+        let mut previous : Option<(Vec<char>, IntType)> = None;
+        let mut position = 0;
+
+        while position < input.len() {
+            self.check_limits(&output)?;
+            self.sample_fill(position, fill_interval);
+            let symbol = input[position];
+
+            let current = if self.dict.contains_key(&[symbol][..]) {
+                let length = self.choose_match(&input[position..]);
+                let phrase = input[position..position+length].to_vec();
+                let codeword = self.dict[&phrase];
+
+                output = self.write_match(output, codeword, self.word_size, length);
+                self.recency.touch(codeword);
+                Some((phrase, codeword))
+            } else {
+                output = self.write_escaped(output, symbol)?;
+
+                // The symbol only counts as a match if it got a codeword
+                let codeword = self.upcoming_word(None);
+                if self.insert(vec![symbol], None) {
+                    codeword.map(|codeword| (vec![symbol], codeword))
+                } else {
+                    None
+                }
+            };
 
-                // Empty read buffer for new symbols
-                sequence.clear();
+            let length = current.as_ref().map_or(1, |(phrase, _)| phrase.len());
+            position += length;
+            self.symbols_since_reset += length;
 
-                // Last character of recently inserted codeword 
-                // is first in new sequence
-                sequence.push(input[i]); 
+            let mut current = current;
+            if let (Some((previous_phrase, previous_word)), Some((phrase, _))) = (&previous, &current) {
+                self.grow(previous_phrase, *previous_word, phrase);
 
-                
-                // Zip bits together tightly
-                output = output.concat(Bits::new(codeword, current_word_size));
+                if !self.has_room() && self.reset_due(output.size) {
+                    if let Some(clear) = self.clear {
+                        output = self.clear_dictionary(output, clear, position);
+                        current = None;
+                    }
+                }
             }
+            previous = current;
         }
 
-        if !sequence.is_empty() {
-            let codeword = self.dict.get(&sequence).expect("encoding should exist 2").clone();
-            output = output.concat(Bits::new(codeword, self.word_size));
+        if let Some(end_of_stream) = self.end_of_stream {
+            output = self.write(output, end_of_stream, self.word_size);
         }
+        self.sample_fill(input.len(), 1);
 
-        output
+        Ok(output)
     }
 }
 
 
 impl LzwDecoder {
-    pub fn new() -> Self {
-        let dict = BTreeMap::<Vec<IntType>, Vec<char>>::new();
+    /// Only `mode`, `max_word_size`, `growth`, `preset` and whether
+    /// `full_policy` is `Lru` need to match the encoder's configuration
+    pub fn new(config : LzwConfig) -> Self {
+        let dict = BTreeMap::<IntType, Vec<char>>::new();
 
         let mut decoder = LzwDecoder {
+            config,
             dict,
             word_size : 0,
-            next_word : vec![0],
+            next_word : 0,
+            escape : None,
+            end_of_stream : None,
+            clear : None,
+            recency : Recency::default(),
+            codes : BTreeMap::new(),
+            widths : vec![],
+            stored_symbols : 0,
+            work : 0,
+            usage : None,
         };
-        
-        for c in ALPHABET.chars() {
-            let codeword = decoder.next_word();
-            decoder.dict.insert(codeword, vec![c]);
+
+        decoder.reset();
+        decoder
+    }
+
+    // Same as LzwEncoder::reset
+    fn reset(&mut self) {
+        self.dict.clear();
+        self.codes.clear();
+        self.recency = Recency::default();
+        self.next_word = 0;
+
+        let mode = self.config.mode;
+
+        for c in mode.alphabet() {
+            let codeword = self.next_word();
+            self.dict.insert(codeword, vec![c]);
+            if self.config.growth != Growth::Classic {
+                self.codes.insert(vec![c], codeword);
+            }
         }
+        self.stored_symbols = self.dict.len() + self.codes.len();
 
-        decoder.word_size = ((decoder.dict.len() - 1).checked_ilog2().unwrap_or(0) + 1) as IntType;
+        if mode.has_escape() {
+            self.escape = Some(self.next_word());
+        }
+        if mode.has_control_words() {
+            self.end_of_stream = Some(self.next_word());
+            self.clear = Some(self.next_word());
+        }
 
-        decoder
+        self.seed_preset();
+        self.track_initial_entries();
     }
 
-    // Same principle as LzwEncoder::next_word
-    fn next_word(&mut self) -> Vec<IntType> {
-        let old = self.next_word.clone();
+    /// Starts the usage of every entry over, the width being the one the
+    /// dictionary starts out with
+    fn track_initial_entries(&mut self) {
+        let width = word_size(self.next_word - 1);
+        if let Some(usage) = &mut self.usage {
+            *usage = self.dict.keys().map(|codeword| (*codeword, (width, 0))).collect();
+        }
+    }
 
-        for n in self.next_word.iter_mut().rev() {
-            *n+=1;
-            if *n != 0 {
-                break
+    fn count_use(&mut self, codeword : IntType) {
+        if let Some((_, uses)) = self.usage.as_mut().and_then(|usage| usage.get_mut(&codeword)) {
+            *uses += 1;
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.next_word < word_limit(self.config.max_word_size)
+    }
+
+    // Same as LzwEncoder::seed_preset
+    fn seed_preset(&mut self) {
+        let preset = self.config.preset.clone();
+        let preset_len = preset.as_ref().map_or(0, |preset| preset.len());
+
+        let largest_word = self.next_word - 1 + preset_len;
+        self.config.max_word_size = self.config.max_word_size.max(word_size(largest_word));
+
+        if let Some(preset) = preset {
+            for phrase in preset.phrases() {
+                self.insert(phrase.clone(), None);
             }
         }
+    }
 
-        if self.next_word[0] == 0 {
-            self.next_word.push(0);
-            self.next_word[0]+=1;
+    // Same as LzwEncoder::upcoming_word
+    fn upcoming_word(&self, parent : Option<IntType>) -> Option<IntType> {
+        if self.has_room() {
+            Some(self.next_word)
+        } else if self.config.full_policy == FullPolicy::Lru {
+            self.recency.least_recent_leaf(parent)
+        } else {
+            None
         }
-        return old
+    }
+
+    // Same principle as LzwEncoder::next_word
+    fn next_word(&mut self) -> IntType {
+        let word = self.next_word;
+        self.next_word += 1;
+        word
     }
 
     /// Inserts a new mapping for Codeword -> Symbol Sequence
-    fn insert(&mut self, sequence : Vec<char>) -> bool {
+    /// 
+    /// Returns false if the sequence already has a codeword, or the dictionary is full.
+    fn insert(&mut self, sequence : Vec<char>, parent : Option<IntType>) -> bool {
+        let tracks_codes = self.config.growth != Growth::Classic;
+        if tracks_codes && self.codes.contains_key(&sequence) {
+            return false;
+        }
+        let Some(codeword) = self.upcoming_word(parent) else {
+            return false;
+        };
+
+        if self.has_room() {
+            self.next_word();
+        } else {
+            // The evicted sequence is overwritten below
+            self.recency.remove(codeword);
+        }
 
-        let next_word = self.next_word();
-        self.dict.insert(next_word, sequence);
+        if let (FullPolicy::Lru, Some(parent)) = (self.config.full_policy, parent) {
+            self.recency.add(codeword, parent);
+        }
 
-        self.word_size = ((self.dict.len()).checked_ilog2().unwrap_or(0) + 1) as IntType;
+        if tracks_codes {
+            self.stored_symbols += sequence.len();
+            self.codes.insert(sequence.clone(), codeword);
+        }
+        self.stored_symbols += sequence.len();
+        if let Some(usage) = &mut self.usage {
+            usage.insert(codeword, (word_size(self.next_word - 1), 0));
+        }
+        if let Some(evicted) = self.dict.insert(codeword, sequence) {
+            self.stored_symbols -= evicted.len();
+            if self.codes.remove(&evicted).is_some() {
+                self.stored_symbols -= evicted.len();
+            }
+        }
 
-        return true
+        true
     }
 
+    // Same as LzwEncoder::grow, checking the limits for each entry: a hostile
+    // stream can make long enough matches that the entries from one of them
+    // alone would exhaust memory, or under `FullPolicy::Lru` evict each
+    // other as fast as they are built
+    fn grow(&mut self, previous : &[char], previous_word : IntType, current : &[char], output : &[char]) -> Result<(), Error> {
+        for length in self.config.growth.extensions(current.len()) {
+            if self.upcoming_word(Some(previous_word)).is_none() {
+                break
+            }
+            self.work += (previous.len() + length) as u64;
+            self.config.limits.check_work(self.work)?;
 
-    pub fn decode(mut self, mut input : Vec<IntType>) -> String {
-        let true_length = input.len();
-        let ratio = INTTYPE_BITS / 8;
-        let disalignment = (ratio - (input.len() as IntType % ratio)) % ratio;
+            let mut sequence = previous.to_vec();
+            sequence.extend(&current[..length]);
+            self.insert(sequence, Some(previous_word));
+            self.check_limits(output)?;
+        }
+        Ok(())
+    }
 
-        input.append(&mut vec![0; disalignment.into()]);
 
-        let input : &[IntType] = bytemuck::cast_slice(&input[..]);
-        let mut output = vec![];
+    /// Decodes a stream produced by `LzwEncoder::encode`.
+    /// 
+    /// Returns the symbols and the number of bits read, the end-of-stream
+    /// codeword included, so that whatever follows the stream can be found.
+    /// Fails with `Error::Corrupt` on a codeword the encoder couldn't have
+    /// written, and with `Error::LimitExceeded` once past `config.limits`.
+    /// Streams with no end-of-stream codeword may end at such a codeword
+    /// instead, if it reaches into their last word: nothing marks where they
+    /// end, so the padding there is read as more.
+    pub fn decode(self, input : Vec<IntType>) -> Result<(Vec<char>, usize), Error> {
+        let (symbols, bits_read, _) = self.decode_with_widths(input)?;
+        Ok((symbols, bits_read))
+    }
 
-        let mut bit_idx = 0;
-        let mut idx = 0;
+    /// Same as `decode`, also counting the codewords read at each width:
+    /// the count at index N is for codewords N bits wide
+    pub fn decode_with_widths(mut self, input : Vec<IntType>) -> Result<(Vec<char>, usize, Vec<usize>), Error> {
+        let (symbols, bits_read) = self.decode_symbols(input)?;
+        Ok((symbols, bits_read, self.widths))
+    }
 
-        let mut sequence_buffer : Vec<char> = vec![];
+    /// Same as `decode`, also returning the dictionary as the stream left
+    /// it, in codeword order. Control words aren't entries.
+    pub fn decode_with_dictionary(mut self, input : Vec<IntType>) -> Result<(Vec<char>, usize, Vec<DictionaryEntry>), Error> {
+        self.usage = Some(BTreeMap::new());
+        self.track_initial_entries();
 
-        let mut last_inserted = vec![];
+        let (symbols, bits_read) = self.decode_symbols(input)?;
 
-        
-        while idx < true_length {
-            let start_idx = idx;
-            let end_idx = idx + (self.word_size + bit_idx).div_ceil(INTTYPE_BITS) as usize;
-            let new_bit_idx = bit_idx + self.word_size;
+        let usage = self.usage.take().unwrap_or_default();
+        let entries = self
+            .dict
+            .into_iter()
+            .map(|(codeword, phrase)| {
+                let (width, uses) = usage.get(&codeword).copied().unwrap_or_default();
+                DictionaryEntry { codeword, width, phrase, uses }
+            })
+            .collect();
+        Ok((symbols, bits_read, entries))
+    }
+
+    fn decode_symbols(&mut self, input : Vec<IntType>) -> Result<(Vec<char>, usize), Error> {
+        if self.config.growth == Growth::Classic {
+            self.decode_classic(input)
+        } else {
+            self.decode_matches(input)
+        }
+    }
+
+    /// Fails once the dictionary or the output are past `config.limits`
+    fn check_limits(&self, output : &[char]) -> Result<(), Error> {
+        let limits = &self.config.limits;
+        limits.check_entries(self.dict.len())?;
+        limits.check_output(output.len() as u64)?;
+        limits.check_memory(
+            dictionary_memory(self.dict.len() + self.codes.len(), self.stored_symbols)
+                + std::mem::size_of_val(output),
+        )
+    }
+
+    /// Whether a codeword missing from the dictionary, just read, ends the
+    /// stream rather than being corrupt. Only the last word is padded.
+    fn ends_in_padding(&self, reader : &BitReader) -> bool {
+        self.end_of_stream.is_none() && reader.remaining() < INTTYPE_BITS
+    }
+
+    fn count_width(&mut self) {
+        if self.widths.len() <= self.word_size {
+            self.widths.resize(self.word_size + 1, 0);
+        }
+        self.widths[self.word_size] += 1;
+    }
 
-            let mut codeword = input[start_idx..=end_idx-1].to_vec();
-            let subslice_length = codeword.len();
+    fn decode_classic(&mut self, input : Vec<IntType>) -> Result<(Vec<char>, usize), Error> {
+        let mut reader = BitReader::new(&input);
+        let mut output = vec![];
 
-            // Zero out irrelevant bits
-            codeword[0] &= IntType::MAX >> bit_idx;
+        let mut sequence_buffer : Vec<char> = vec![];
+        // Codeword sequence_buffer came from
+        let mut previous = None;
 
-            // Shift entire sequence to the right, by number of irrelevant bits
-            let right_space = codeword.len() as IntType * INTTYPE_BITS - bit_idx - self.word_size;
+        
+        loop {
+            self.check_limits(&output)?;
+
+            // Past the first codeword the encoder is one dictionary entry
+            // ahead of us, as long as it could make one
+            let upcoming = if sequence_buffer.is_empty() {
+                None
+            } else {
+                self.upcoming_word(previous)
+            };
+
+            let largest_word = match upcoming {
+                Some(_) if self.has_room() => self.next_word,
+                _ => self.next_word - 1,
+            };
+            self.word_size = word_size(largest_word);
+
+            // Too few bits left for a whole codeword, only padding remains
+            let Some(codeword) = reader.read(self.word_size) else {
+                break
+            };
+            self.count_width();
 
+            if Some(codeword) == self.end_of_stream {
+                break
+            }
 
-            codeword[subslice_length-1] >>= right_space;
-            for i in (1..subslice_length).rev() {
-                codeword[i] |= codeword[i-1] << (INTTYPE_BITS - right_space);
-                codeword[i-1] >>= right_space;
+            if Some(codeword) == self.clear {
+                self.reset();
+                sequence_buffer.clear();
+                previous = None;
+                continue
             }
 
-            // Remove irrelevant 0 elements to make sure sequence exists in dictionary
-            codeword = codeword.into_iter().skip_while(|a| *a==0).collect::<Vec<IntType>>();
-            if codeword.len() == 0 {
-                codeword = vec![0];
+            if Some(codeword) == self.escape {
+                let Some(raw) = reader.read(RAW_SYMBOL_BITS) else {
+                    break
+                };
+                let symbol = char::from_u32(raw as u32).ok_or(Error::Corrupt("escaped symbol is not a char"))?;
+
+                // Mirror the entries the encoder made around the escape
+                if !sequence_buffer.is_empty() {
+                    sequence_buffer.push(symbol);
+                    self.insert(sequence_buffer.clone(), previous);
+                    sequence_buffer.clear();
+                }
+                self.insert(vec![symbol], None);
+
+                output.push(symbol);
+                previous = None;
+                continue
             }
 
-            let characters = self.dict.get(&codeword).unwrap_or_else(
-                || {
-                    last_inserted.push(last_inserted[0]);
-                    &last_inserted
+            let characters = if Some(codeword) == upcoming {
+                // cScSc case: the codeword is the entry we're about to
+                // insert, made from the previous sequence and its own first symbol
+                let mut characters = sequence_buffer.clone();
+                characters.push(sequence_buffer[0]);
+                characters
+            } else {
+                match self.dict.get(&codeword) {
+                    Some(characters) => characters.clone(),
+                    None if self.ends_in_padding(&reader) => break,
+                    None => return Err(Error::Corrupt("codeword is not in the dictionary")),
                 }
-            ).clone();
+            };
 
             output.extend(characters.clone());
 
             if !sequence_buffer.is_empty() {
                 sequence_buffer.push(characters[0]);
-                self.insert(sequence_buffer.clone());
-
-                // Handle cScSc case
-                last_inserted = sequence_buffer.clone();
+                self.insert(sequence_buffer.clone(), previous);
 
                 sequence_buffer.clear()
             }
 
+            self.recency.touch(codeword);
+            self.count_use(codeword);
+
             sequence_buffer.extend(characters);
-            
-            bit_idx = new_bit_idx % INTTYPE_BITS;
-            idx = end_idx-1;
-            if bit_idx == 0 {
-                idx+=1;
+            previous = Some(codeword);
+        }
+
+        self.check_limits(&output)?;
+        Ok((output, reader.position()))
+    }
+
+    /// Mirrors `LzwEncoder::encode_matches`
+    fn decode_matches(&mut self, input : Vec<IntType>) -> Result<(Vec<char>, usize), Error> {
+        let mut reader = BitReader::new(&input);
+        let mut output = vec![];
+
+        let mut previous : Option<(Vec<char>, IntType)> = None;
+
+        loop {
+            self.check_limits(&output)?;
+            self.word_size = word_size(self.next_word - 1);
+
+            let Some(codeword) = reader.read(self.word_size) else {
+                break
+            };
+            self.count_width();
+
+            if Some(codeword) == self.end_of_stream {
+                break
+            }
+
+            if Some(codeword) == self.clear {
+                self.reset();
+                previous = None;
+                continue
+            }
+
+            let current = if Some(codeword) == self.escape {
+                let Some(raw) = reader.read(RAW_SYMBOL_BITS) else {
+                    break
+                };
+                let symbol = char::from_u32(raw as u32).ok_or(Error::Corrupt("escaped symbol is not a char"))?;
+                output.push(symbol);
+
+                let codeword = self.upcoming_word(None);
+                if self.insert(vec![symbol], None) {
+                    codeword.map(|codeword| (vec![symbol], codeword))
+                } else {
+                    None
+                }
+            } else {
+                let phrase = match self.dict.get(&codeword) {
+                    Some(phrase) => phrase.clone(),
+                    None if self.ends_in_padding(&reader) => break,
+                    None => return Err(Error::Corrupt("codeword is not in the dictionary")),
+                };
+                output.extend(&phrase);
+                self.recency.touch(codeword);
+                self.count_use(codeword);
+                Some((phrase, codeword))
+            };
+
+            if let (Some((previous_phrase, previous_word)), Some((phrase, _))) = (&previous, &current) {
+                self.grow(previous_phrase, *previous_word, phrase, &output)?;
             }
+            previous = current;
         }
 
-        output
-            .into_iter()
-            .collect::<String>()
+        self.check_limits(&output)?;
+        Ok((output, reader.position()))
+    }
+}
+
+
+#[cfg(test)]
+mod lzw_test {
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use crate::lzw::*;
+    use crate::u8_to_usize;
+
+    fn round_trip(config : LzwConfig, input : &[char]) -> Vec<char> {
+        let encoded = LzwEncoder::new(config.clone()).encode(input.to_vec()).unwrap();
+        let (decoded, bits_read) = LzwDecoder::new(config).decode(encoded.bits.clone()).unwrap();
+
+        assert_eq!(bits_read, encoded.size);
+        decoded
+    }
+
+    /// The Norwegian folktale twice, Russian ones in Cyrillic, then the
+    /// Norwegian again, so statistics settle, shift and come back
+    fn mixed_corpus() -> Vec<char> {
+        let folktale = include_str!("../folktale.txt");
+        [folktale, folktale, include_str!("../folktale-ru.txt"), folktale].join(" ").chars().collect()
+    }
+
+    /// Runs, which make cScSc codewords, emoji, which text mode escapes,
+    /// then prose, enough to fill a 9-bit table long before the end
+    fn awkward_text() -> String {
+        let mut text = "aaaaaaaaaaaaaaaaaaaaab".repeat(40);
+        text.push_str(&"😀 🙂 😀 ".repeat(30));
+        text.extend(mixed_corpus().into_iter().take(3000));
+        text
+    }
+
+    fn encoded_size(config : LzwConfig, input : &[char]) -> IntType {
+        LzwEncoder::new(config).encode(input.to_vec()).unwrap().size
+    }
+
+    #[test]
+    fn limits_stop_both_coders() {
+        use crate::limits::{Limit, Limits};
+
+        let input = mixed_corpus();
+        let limited = |limits| LzwConfig { limits, ..LzwConfig::new(Mode::Text) };
+        let encoded = LzwEncoder::new(LzwConfig::new(Mode::Text)).encode(input.clone()).unwrap();
+        let decode = |limits| LzwDecoder::new(limited(limits)).decode(encoded.bits.clone());
+
+        let entries = Limits { max_entries : Some(1000), ..Limits::none() };
+        assert!(matches!(
+            LzwEncoder::new(limited(entries)).encode(input.clone()),
+            Err(Error::LimitExceeded { limit : Limit::Entries, max : 1000 })
+        ));
+        assert!(matches!(decode(entries), Err(Error::LimitExceeded { limit : Limit::Entries, .. })));
+
+        let output = |max| Limits { max_output : Some(max), ..Limits::none() };
+        assert!(matches!(decode(output(input.len() as u64 - 1)), Err(Error::LimitExceeded { limit : Limit::Output, .. })));
+        assert_eq!(decode(output(input.len() as u64)).unwrap().0, input);
+
+        let memory = Limits { max_memory : Some(100_000), ..Limits::none() };
+        assert!(matches!(decode(memory), Err(Error::LimitExceeded { limit : Limit::Memory, .. })));
+        assert!(matches!(
+            LzwEncoder::new(limited(memory)).encode(input.clone()),
+            Err(Error::LimitExceeded { limit : Limit::Memory, .. })
+        ));
+    }
+
+    #[test]
+    fn long_runs_are_stopped_early() {
+        use crate::limits::{Limit, Limits};
+
+        // Each codeword decodes to one more symbol than the last, so the
+        // output grows with the square of the stream
+        let config = LzwConfig { max_word_size : 24, full_policy : FullPolicy::Freeze, ..LzwConfig::new(Mode::Bytes) };
+        let encoded = LzwEncoder::new(config.clone()).encode(vec!['\0'; 200_000]).unwrap();
+        assert!(encoded.size / 8 < 1000);
+
+        let limits = Limits { max_output : Some(10_000), max_memory : Some(1 << 20), ..Limits::none() };
+        assert!(matches!(
+            LzwDecoder::new(LzwConfig { limits, ..config }).decode(encoded.bits),
+            Err(Error::LimitExceeded { limit : Limit::Output, max : 10_000 })
+        ));
+    }
+
+    #[test]
+    fn hostile_streams_fail_cleanly() {
+        use crate::limits::{Limit, Limits};
+
+        let lzap = LzwConfig { growth : Growth::Lzap, ..LzwConfig::new(Mode::Bytes) };
+        assert_eq!(LzwDecoder::new(LzwConfig::new(Mode::Text)).decode(vec![]).unwrap(), (vec![], 0));
+        for config in [LzwConfig::new(Mode::Bytes), lzap.clone()] {
+            // The first codeword is already past the dictionary
+            assert!(matches!(LzwDecoder::new(config).decode(vec![usize::MAX]), Err(Error::Corrupt(_))));
+        }
+
+        // Sending the newest entry every time makes LZAP matches grow like
+        // Fibonacci numbers, and the entries made from each one with their
+        // square. In bytes mode a run of N 'a's is codeword 256 + N.
+        let mut stream = Bits { bits : vec![], size : 0 };
+        for _ in 0..2 {
+            stream = stream.concat(Bits::new(vec![97], 9));
+        }
+        let (mut previous, mut longest) = (1, 2);
+        for _ in 0..30 {
+            stream = stream.concat(Bits::new(vec![256 + longest], word_size(256 + longest)));
+            (previous, longest) = (longest, previous + longest);
+        }
+
+        let limits = Limits { max_memory : Some(1 << 24), ..Limits::none() };
+        assert!(matches!(
+            LzwDecoder::new(LzwConfig { limits, ..lzap }).decode(stream.bits),
+            Err(Error::LimitExceeded { limit : Limit::Memory, .. })
+        ));
+    }
+
+    #[test]
+    fn hostile_growth_stops_at_the_work_limit() {
+        use crate::limits::{Limit, Limits};
+
+        // Two 'a's, then the newest entry every time, as above. Evicting
+        // keeps the dictionary small, so neither memory nor output limits
+        // stopped the entries from each match taking longer to build, and
+        // this ran for minutes.
+        let stream = [
+            0x02, 0x07, 0x5e, 0xcd, 0xdc, 0x7b, 0x7e, 0x8b, 0xa0, 0xc2, 0xf9, 0xa9, 0x78,
+            0xa8, 0x13, 0x03, 0xba, 0x02, 0x9e, 0xac, 0x77, 0xac, 0xdf, 0x86, 0x90,
+        ];
+        let config = LzwConfig {
+            max_word_size : 9,
+            full_policy : FullPolicy::Lru,
+            growth : Growth::Lzap,
+            ..LzwConfig::new(Mode::Text)
+        };
+        let decode = |limits| LzwDecoder::new(LzwConfig { limits, ..config.clone() }).decode(u8_to_usize(stream.to_vec()));
+
+        let limits = Limits { max_output : Some(1 << 22), max_memory : Some(1 << 28), max_work : Some(1 << 22), ..Limits::none() };
+        assert!(matches!(decode(limits), Err(Error::LimitExceeded { limit : Limit::Work, max : 4194304 })));
+
+        // Plain LZW copies no more into its entries than it outputs
+        let input = "abracadabra".chars().collect::<Vec<char>>();
+        let encoded = LzwEncoder::new(LzwConfig::new(Mode::Text)).encode(input.clone()).unwrap();
+        let limits = Limits { max_work : Some(input.len() as u64), ..Limits::none() };
+        assert_eq!(LzwDecoder::new(LzwConfig { limits, ..LzwConfig::new(Mode::Text) }).decode(encoded.bits).unwrap().0, input);
+    }
+
+    #[test]
+    fn fuzz_regressions_fail_cleanly() {
+        use crate::limits::{Limit, Limits};
+
+        // Inputs the lzw_decoder fuzz target found slow, configured the way
+        // it does from the first byte, under its limits
+        let limits = Limits { max_output : Some(1 << 22), max_memory : Some(1 << 28), max_work : Some(1 << 24), ..Limits::none() };
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions/lzw_decoder");
+
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            let (&settings, stream) = data.split_first().unwrap();
+            let settings = usize::from(settings);
+
+            let config = LzwConfig {
+                max_word_size : 9 + settings / 36 % 8,
+                full_policy : [
+                    FullPolicy::Freeze,
+                    FullPolicy::Clear,
+                    FullPolicy::Lru,
+                    FullPolicy::AdaptiveClear { threshold : 0 },
+                ][settings / 9 % 4],
+                growth : [Growth::Classic, Growth::Lzmw, Growth::Lzap][settings / 3 % 3],
+                limits,
+                ..LzwConfig::new([Mode::Bytes, Mode::Text, Mode::Legacy][settings % 3])
+            };
+            assert!(
+                matches!(
+                    LzwDecoder::new(config).decode(u8_to_usize(stream.to_vec())),
+                    Err(Error::LimitExceeded { limit : Limit::Work, .. })
+                ),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn stops_at_end_of_stream() {
+        // Every length crosses codeword width changes at a different point
+        let text = include_str!("../folktale.txt").chars().take(300).collect::<Vec<char>>();
+
+        for mode in [Mode::Bytes, Mode::Text] {
+            for length in 0..text.len() {
+                let input = mode.symbols(&text[..length].iter().collect::<String>().into_bytes()).unwrap();
+                assert_eq!(round_trip(LzwConfig::new(mode), &input), input);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_streams_end_at_an_unknown_codeword_in_their_last_word() {
+        // Whatever follows a legacy stream is decoded too, and may hold any bits
+        let input = "abba babba".chars().collect::<Vec<char>>();
+        for growth in [Growth::Classic, Growth::Lzmw, Growth::Lzap] {
+            let config = LzwConfig { max_word_size : INTTYPE_BITS, growth, ..LzwConfig::new(Mode::Legacy) };
+            let mut encoded = LzwEncoder::new(config.clone()).encode(input.clone()).unwrap();
+            encoded.bits.push(IntType::MAX);
+
+            let (decoded, _) = LzwDecoder::new(config.clone()).decode(encoded.bits.clone()).unwrap();
+            assert!(decoded.starts_with(&input), "{growth:?}");
+
+            // With more words after it, the stream is corrupt
+            encoded.bits.push(0);
+            let result = LzwDecoder::new(config).decode(encoded.bits);
+            assert!(matches!(result, Err(Error::Corrupt("codeword is not in the dictionary"))), "{growth:?}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let first = "Det var en gang".chars().collect::<Vec<char>>();
+        let second = "en fattigmann 😀".chars().collect::<Vec<char>>();
+
+        let mut bytes = LzwEncoder::new(LzwConfig::new(Mode::Text)).encode(first.clone()).unwrap().to_bytes();
+        let offset = bytes.len();
+        bytes.extend(LzwEncoder::new(LzwConfig::new(Mode::Text)).encode(second.clone()).unwrap().to_bytes());
+
+        let (decoded, bits_read) = LzwDecoder::new(LzwConfig::new(Mode::Text)).decode(u8_to_usize(bytes.clone())).unwrap();
+        assert_eq!(decoded, first);
+        assert_eq!(bits_read.div_ceil(8), offset);
+
+        let (decoded, _) = LzwDecoder::new(LzwConfig::new(Mode::Text)).decode(u8_to_usize(bytes[offset..].to_vec())).unwrap();
+        assert_eq!(decoded, second);
+    }
+
+    #[test]
+    fn full_dictionary_policies() {
+        let text = mixed_corpus().into_iter().collect::<String>();
+
+        for full_policy in [
+            FullPolicy::Freeze,
+            FullPolicy::Clear,
+            FullPolicy::AdaptiveClear { threshold : 0 },
+            FullPolicy::AdaptiveClear { threshold : 10 },
+            FullPolicy::Lru,
+        ] {
+            for mode in [Mode::Bytes, Mode::Text] {
+                let input = mode.symbols(text.as_bytes()).unwrap();
+                let config = LzwConfig { max_word_size : 9, full_policy, ..LzwConfig::new(mode) };
+                assert_eq!(round_trip(config, &input), input);
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_clear_beats_freeze_and_clear_on_shifting_input() {
+        let input = mixed_corpus();
+
+        for max_word_size in [10, 11] {
+            let config = |full_policy| LzwConfig { max_word_size, full_policy, ..LzwConfig::new(Mode::Text) };
+
+            let frozen = encoded_size(config(FullPolicy::Freeze), &input);
+            let cleared = encoded_size(config(FullPolicy::Clear), &input);
+            let adaptive = encoded_size(config(FullPolicy::AdaptiveClear { threshold : 0 }), &input);
+
+            assert!(adaptive < frozen, "{max_word_size} bits: adaptive {adaptive} vs frozen {frozen}");
+            assert!(adaptive < cleared, "{max_word_size} bits: adaptive {adaptive} vs cleared {cleared}");
+        }
+    }
+
+    #[test]
+    fn lru_reuses_codewords() {
+        let text = awkward_text();
+
+        for mode in [Mode::Bytes, Mode::Text] {
+            let input = mode.symbols(text.as_bytes()).unwrap();
+            let config = |full_policy| LzwConfig { max_word_size : 9, full_policy, ..LzwConfig::new(mode) };
+            assert_eq!(round_trip(config(FullPolicy::Lru), &input), input);
+
+            // The table stays at 9 bits and never clears, yet keeps learning
+            // where a frozen one can't
+            let (encoded, stats) = LzwEncoder::new(config(FullPolicy::Lru)).encode_with_stats(input.clone()).unwrap();
+            assert!(stats.resets.is_empty(), "{mode:?}");
+            assert!(stats.widths.len() <= 10, "{mode:?}");
+            let frozen = encoded_size(config(FullPolicy::Freeze), &input);
+            assert!(encoded.size < frozen, "{mode:?}: lru {} vs frozen {frozen}", encoded.size);
+        }
+    }
+
+    #[test]
+    fn lru_beats_freeze_on_shifting_input() {
+        let input = mixed_corpus();
+
+        for max_word_size in [10, 11] {
+            let config = |full_policy| LzwConfig { max_word_size, full_policy, ..LzwConfig::new(Mode::Text) };
+
+            let frozen = encoded_size(config(FullPolicy::Freeze), &input);
+            let lru = encoded_size(config(FullPolicy::Lru), &input);
+
+            assert!(lru < frozen, "{max_word_size} bits: lru {lru} vs frozen {frozen}");
+        }
+    }
+
+    #[test]
+    fn growth_variants_round_trip() {
+        let text = awkward_text();
+
+        for growth in [Growth::Lzmw, Growth::Lzap] {
+            for full_policy in [FullPolicy::Freeze, FullPolicy::Clear, FullPolicy::Lru] {
+                for mode in [Mode::Bytes, Mode::Text] {
+                    let input = mode.symbols(text.as_bytes()).unwrap();
+
+                    for max_word_size in [9, 16] {
+                        let config = LzwConfig { max_word_size, full_policy, growth, ..LzwConfig::new(mode) };
+                        assert_eq!(round_trip(config, &input), input, "{growth:?} {full_policy:?} {mode:?}");
+                    }
+                }
+            }
+        }
+
+        // Classic growth adds a symbol to a match, these add a whole match,
+        // so the runs soon go out many repeats at a time
+        for mode in [Mode::Bytes, Mode::Text] {
+            let input = mode.symbols(text.as_bytes()).unwrap();
+            let longest = |growth| {
+                let config = LzwConfig { growth, ..LzwConfig::new(mode) };
+                let (_, stats) = LzwEncoder::new(config).encode_with_stats(input.clone()).unwrap();
+                stats.match_lengths.len() - 1
+            };
+
+            let classic = longest(Growth::Classic);
+            for growth in [Growth::Lzmw, Growth::Lzap] {
+                let longest = longest(growth);
+                assert!(longest > 4 * classic, "{mode:?} {growth:?}: longest {longest} vs classic {classic}");
+            }
+        }
+    }
+
+    #[test]
+    fn growth_variants_learn_repeats_faster() {
+        let folktale = include_str!("../folktale.txt");
+        let size = |growth, text : &str| {
+            let input : Vec<char> = text.chars().collect();
+            encoded_size(LzwConfig { growth, ..LzwConfig::new(Mode::Text) }, &input)
+        };
+        let once = |growth| size(growth, folktale);
+        // What telling the folktale a second time costs on top of the first
+        let again = |growth| size(growth, &folktale.repeat(2)) - once(growth);
+
+        let (classic_once, classic_again) = (once(Growth::Classic), again(Growth::Classic));
+        for growth in [Growth::Lzmw, Growth::Lzap] {
+            let (once, again) = (once(growth), again(growth));
+            assert!(once < classic_once, "{growth:?} {once} vs classic {classic_once}");
+            assert!(3 * again < 2 * classic_again, "{growth:?} {again} again vs classic {classic_again}");
+        }
+    }
+
+    #[test]
+    fn flexible_parsing_round_trips() {
+        let text = awkward_text();
+
+        for growth in [Growth::Classic, Growth::Lzmw, Growth::Lzap] {
+            let mut departures = 0;
+
+            for full_policy in [FullPolicy::Freeze, FullPolicy::Clear, FullPolicy::Lru] {
+                for mode in [Mode::Bytes, Mode::Text] {
+                    let input = mode.symbols(text.as_bytes()).unwrap();
+                    let config = |parsing| LzwConfig {
+                        max_word_size : 9,
+                        full_policy,
+                        growth,
+                        parsing,
+                        ..LzwConfig::new(mode)
+                    };
+                    assert_eq!(
+                        round_trip(config(Parsing::Flexible), &input),
+                        input,
+                        "{growth:?} {full_policy:?} {mode:?}"
+                    );
+
+                    let encode = |parsing| LzwEncoder::new(config(parsing)).encode(input.clone()).unwrap().bits;
+                    departures += usize::from(encode(Parsing::Flexible) != encode(Parsing::Greedy));
+                }
+            }
+
+            // Otherwise the decoder never saw a match cut short
+            assert!(departures > 0, "{growth:?}");
+        }
+    }
+
+    #[test]
+    fn flexible_parsing_beats_greedy_on_repeats() {
+        let input : Vec<char> = include_str!("../folktale.txt").repeat(3).chars().collect();
+
+        for growth in [Growth::Classic, Growth::Lzmw, Growth::Lzap] {
+            let config = |parsing| LzwConfig { growth, parsing, ..LzwConfig::new(Mode::Text) };
+
+            let greedy = encoded_size(config(Parsing::Greedy), &input);
+            let flexible = encoded_size(config(Parsing::Flexible), &input);
+            assert!(flexible < greedy, "{growth:?}: flexible {flexible} vs greedy {greedy}");
+        }
+    }
+
+    #[test]
+    fn levels_trade_speed_for_size() {
+        let input : Vec<char> = include_str!("../folktale.txt").repeat(3).chars().collect();
+
+        let sizes = (1..=9)
+            .map(|level| {
+                let config = LzwConfig::level(Mode::Text, level);
+                assert_eq!(round_trip(config.clone(), &input), input, "level {level}");
+                encoded_size(config, &input)
+            })
+            .collect::<Vec<IntType>>();
+
+        assert!(sizes.windows(2).all(|pair| pair[1] <= pair[0]), "{sizes:?}");
+        assert!(sizes[8] < sizes[0], "{sizes:?}");
+    }
+
+    #[test]
+    fn stats_follow_the_dictionary_through_resets() {
+        let input = mixed_corpus();
+        let config = LzwConfig { max_word_size : 10, full_policy : FullPolicy::Clear, ..LzwConfig::new(Mode::Text) };
+        let (_, stats) = LzwEncoder::new(config).encode_with_stats(input.clone()).unwrap();
+
+        assert_eq!(stats.capacity, 1024);
+        assert!(!stats.resets.is_empty());
+        assert!(stats.fill.len() <= FILL_SAMPLES + 2 * stats.resets.len() + 1);
+        assert_eq!(stats.fill.last().unwrap().0, input.len());
+
+        // Full right before each reset, back to the alphabet right after
+        let initial = Mode::Text.initial_words();
+        for reset in &stats.resets {
+            let at = stats.fill.iter().position(|(position, _)| position == reset).unwrap();
+            assert_eq!(stats.fill[at].1, 1024);
+            assert_eq!(stats.fill[at + 1], (*reset, initial));
+        }
+
+        let (_, unlimited) = LzwEncoder::new(LzwConfig::new(Mode::Text)).encode_with_stats(input).unwrap();
+        assert!(unlimited.resets.is_empty());
+        assert!(unlimited.match_lengths.len() > stats.match_lengths.len());
+    }
+
+
+    #[test]
+    fn decoded_dictionary_counts_every_match() {
+        let input = mixed_corpus();
+
+        for growth in [Growth::Classic, Growth::Lzmw, Growth::Lzap] {
+            let config = LzwConfig { full_policy : FullPolicy::Freeze, max_word_size : 12, growth, ..LzwConfig::new(Mode::Text) };
+            let (encoded, stats) = LzwEncoder::new(config.clone()).encode_with_stats(input.clone()).unwrap();
+            let (decoded, _, entries) = LzwDecoder::new(config).decode_with_dictionary(encoded.bits).unwrap();
+            assert_eq!(decoded, input);
+
+            // Nothing is evicted, so every match is still counted
+            let matches = stats.match_lengths.iter().sum::<usize>();
+            assert_eq!(entries.iter().map(|entry| entry.uses).sum::<usize>(), matches, "{growth:?}");
+            assert_eq!(entries.len(), 4096 - 3, "{growth:?}");
+
+            // Escape, end-of-stream and clear aren't entries
+            let initial = Mode::Text.initial_words();
+            assert!(entries[..initial - 3].iter().all(|entry| entry.width == 7 && entry.phrase.len() == 1));
+            assert!(entries.iter().all(|entry| entry.codeword < initial - 3 || entry.codeword >= initial));
+            assert!(entries.windows(2).all(|pair| pair[0].codeword < pair[1].codeword && pair[0].width <= pair[1].width));
+            assert_eq!(entries.last().unwrap().width, 12);
+        }
+    }
+
+    /// Symbols `mode` can encode. Text mode gets some it has to escape too.
+    fn symbol(mode : Mode) -> BoxedStrategy<char> {
+        let alphabet = select(mode.alphabet());
+        match mode {
+            Mode::Text => prop_oneof![4 => alphabet, 1 => any::<char>()].boxed(),
+            Mode::Bytes | Mode::Legacy => alphabet.boxed(),
+        }
+    }
+
+    /// Anything over the alphabet, a short pattern over and over, and the
+    /// cScSc case (KwKwK), where a codeword is read right as it's made
+    fn symbols(mode : Mode) -> impl Strategy<Value = Vec<char>> {
+        let random = vec(symbol(mode), 0..600);
+        let repeated = (vec(symbol(mode), 1..6), 1..200usize).prop_map(|(pattern, times)| pattern.repeat(times));
+        let cscsc = (symbol(mode), vec(symbol(mode), 0..4), 1..40usize).prop_map(|(c, s, times)| {
+            let mut input = [&[c], &s[..]].concat().repeat(times);
+            input.push(c);
+            input
+        });
+        prop_oneof![random, repeated, cscsc]
+    }
+
+    /// Any configuration for `mode`, narrow enough that the dictionary
+    /// fills up, sometimes with a preset dictionary
+    fn config(mode : Mode) -> impl Strategy<Value = LzwConfig> {
+        let full_policy = prop_oneof![
+            Just(FullPolicy::Freeze),
+            Just(FullPolicy::Clear),
+            (0..20u8).prop_map(|threshold| FullPolicy::AdaptiveClear { threshold }),
+            Just(FullPolicy::Lru),
+        ];
+        let growth = select(vec![Growth::Classic, Growth::Lzmw, Growth::Lzap]);
+        let parsing = select(vec![Parsing::Greedy, Parsing::Flexible]);
+        let preset = option::of(vec(vec(symbol(mode), 2..6), 1..20))
+            .prop_map(move |phrases| phrases.and_then(|phrases| PresetDictionary::new(mode, phrases).ok()).map(Arc::new));
+
+        (9..=12 as IntType, full_policy, growth, parsing, preset).prop_map(
+            move |(max_word_size, full_policy, growth, parsing, preset)| LzwConfig {
+                max_word_size,
+                full_policy,
+                growth,
+                parsing,
+                preset,
+                ..LzwConfig::new(mode)
+            },
+        )
+    }
+
+    fn mode() -> impl Strategy<Value = Mode> {
+        select(vec![Mode::Bytes, Mode::Text, Mode::Legacy])
+    }
+
+    proptest! {
+        #[test]
+        fn every_configuration_round_trips(
+            (config, input) in mode().prop_flat_map(|mode| (config(mode), symbols(mode)))
+        ) {
+            let mode = config.mode;
+            let encoded = LzwEncoder::new(config.clone()).encode(input.clone()).unwrap();
+            let (decoded, bits_read) = LzwDecoder::new(config).decode(encoded.bits.clone()).unwrap();
+
+            if mode.has_control_words() {
+                prop_assert_eq!(decoded, input);
+                prop_assert_eq!(bits_read, encoded.size);
+            } else {
+                // Without an end-of-stream codeword the padding in the last
+                // word decodes too, as codeword 0
+                prop_assert_eq!(&decoded[..input.len()], &input[..]);
+                prop_assert!(decoded[input.len()..].iter().all(|symbol| *symbol == ' '));
+            }
+        }
+
+        #[test]
+        fn stats_describe_what_was_written(
+            (config, input) in mode().prop_flat_map(|mode| (config(mode), symbols(mode)))
+        ) {
+            let encoded = LzwEncoder::new(config.clone()).encode(input.clone()).unwrap();
+            let (with_stats, stats) = LzwEncoder::new(config.clone()).encode_with_stats(input.clone()).unwrap();
+            prop_assert_eq!(&with_stats, &encoded);
+
+            // Every symbol is in a match or escaped
+            let matched = stats.match_lengths.iter().enumerate().map(|(length, n)| length * n).sum::<usize>();
+            prop_assert_eq!(matched + stats.escapes, input.len());
+
+            if config.mode.has_control_words() {
+                let (_, _, widths) = LzwDecoder::new(config).decode_with_widths(encoded.bits).unwrap();
+                prop_assert_eq!(&stats.widths, &widths);
+            }
+
+            prop_assert_eq!(stats.fill.last().map(|(at, _)| *at), Some(input.len()));
+            prop_assert!(stats.fill.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+            prop_assert!(stats.fill.iter().all(|(_, taken)| *taken <= stats.capacity));
+        }
     }
 }
@@ -1,326 +1,1806 @@
-mod lzw;
-use lzw::*;
+mod bench;
 
-mod huffman;
-use huffman::*;
+use lzw::archive::*;
+use lzw::container::*;
+use lzw::error::Error;
+use lzw::huffman::HuffmanStage;
+use lzw::limits::Limits;
+use lzw::lzw::*;
+use lzw::preset::PresetDictionary;
 
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
-use clap::{Arg, ArgAction, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 
-type IntType = usize;
+/// Flag IDs of the levels, `-1` to `-9`
+const LEVELS: [&str; 9] = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
 
-const INTTYPE_BITS : IntType = (0 as IntType).count_zeros() as IntType;
+const DEFAULT_LEVEL: u8 = 6;
 
-fn main() {
-    let matches = Command::new("lzw")
+fn cli() -> Command {
+    Command::new("lzw")
         .about("LZW implementation")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("train")
+                .about("Build a preset dictionary from a directory of sample files")
+                .arg(
+                    Arg::new("samples")
+                        .help("Directory of sample files")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .default_value("dictionary.lzwd")
+                        .help("Where to write the dictionary"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .action(ArgAction::SetTrue)
+                        .short('f')
+                        .long("force")
+                        .help("Overwrite an existing dictionary"),
+                )
+                .arg(
+                    Arg::new("text")
+                        .action(ArgAction::SetTrue)
+                        .long("text")
+                        .help("Train for text mode. Samples must be UTF-8"),
+                )
+                .arg(
+                    Arg::new("max-bits")
+                        .long("max-bits")
+                        .value_parser(clap::value_parser!(u8).range(9..=24))
+                        .default_value("16")
+                        .help("Widest codeword the dictionary will be used with. \
+                            The phrases fill what the alphabet leaves of 2^N entries"),
+                )
+                .arg(
+                    Arg::new("entries")
+                        .long("entries")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Keep fewer phrases than the table has room for"),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .visible_alias("list")
+                .about("Describe compressed files from their headers")
+                .arg(
+                    Arg::new("files")
+                        .help("Compressed files")
+                        .num_args(1..)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("quick")
+                        .action(ArgAction::SetTrue)
+                        .short('q')
+                        .long("quick")
+                        .help("Skip the codeword width statistics, which need the LZW stream decoded"),
+                )
+                .arg(
+                    Arg::new("hm")
+                        .action(ArgAction::SetTrue)
+                        .long("hm")
+                        .help("Headerless files have the huffman stage"),
+                )
+                .arg(
+                    Arg::new("dict")
+                        .long("dict")
+                        .help("Preset dictionary, for the width statistics of files made with one"),
+                )
+                .args(limit_args()),
+        )
+        .subcommand(
+            Command::new("dump-dict")
+                .about("Export the dictionary a compressed file ends with, and its Huffman tree")
+                .arg(Arg::new("file").help("Compressed file").required(true))
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["json", "dot"])
+                        .default_value("json")
+                        .help("JSON, or Graphviz DOT drawing the dictionary as a trie"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Write to this file instead of standard output"),
+                )
+                .arg(
+                    Arg::new("block")
+                        .long("block")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("1")
+                        .help("Of files compressed in blocks, the block whose dictionary to export"),
+                )
+                .arg(
+                    Arg::new("hm")
+                        .action(ArgAction::SetTrue)
+                        .long("hm")
+                        .help("Headerless files have the huffman stage"),
+                )
+                .arg(
+                    Arg::new("dict")
+                        .long("dict")
+                        .help("Preset dictionary the file was made with"),
+                )
+                .args(limit_args()),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Run every codec over a directory of files and compare them")
+                .arg(
+                    Arg::new("directory")
+                        .help("Corpus to run over, subdirectories included")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .help("Only run codecs whose flags contain this, like --growth or -9"),
+                )
+                .arg(
+                    Arg::new("csv")
+                        .long("csv")
+                        .help("Also write the results to this file as CSV"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Also write the results to this file as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("archive")
+                .about("Bundle files with their metadata into one archive, or unpack one")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Archive files and directories, compressing each file on its own")
+                        .arg(Arg::new("archive").help("Archive to write").required(true))
+                        .arg(
+                            Arg::new("paths")
+                                .help("Files and directories to add")
+                                .num_args(1..)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("hm")
+                                .action(ArgAction::SetTrue)
+                                .long("hm")
+                                .help("Apply huffman codec after lzw. Levels 8 and 9 do when it helps"),
+                        )
+                        .arg(
+                            Arg::new("text")
+                                .action(ArgAction::SetTrue)
+                                .long("text")
+                                .help("Encode Unicode characters instead of bytes. Files that aren't UTF-8 are stored"),
+                        )
+                        .args(level_args())
+                        .arg(
+                            Arg::new("force")
+                                .action(ArgAction::SetTrue)
+                                .short('f')
+                                .long("force")
+                                .help("Overwrite an existing archive"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("Show the entries of an archive")
+                        .arg(Arg::new("archive").help("Archive to read").required(true)),
+                )
+                .subcommand(
+                    Command::new("extract")
+                        .about("Unpack an archive, restoring permissions and modification times")
+                        .arg(Arg::new("archive").help("Archive to read").required(true))
+                        .arg(
+                            Arg::new("paths")
+                                .help("Entries to extract. All of them if none are given")
+                                .num_args(0..),
+                        )
+                        .arg(
+                            Arg::new("directory")
+                                .short('C')
+                                .long("directory")
+                                .default_value(".")
+                                .help("Directory to extract into"),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .action(ArgAction::SetTrue)
+                                .short('f')
+                                .long("force")
+                                .help("Overwrite existing files"),
+                        )
+                        .args(limit_args()),
+                ),
+        )
         .arg(
             Arg::new("decompress")
                 .action(ArgAction::SetTrue)
                 .short('d')
+                .long("decompress")
                 .help("Decompress target file instead of compressing"),
         )
         .arg(
             Arg::new("hm")
                 .action(ArgAction::SetTrue)
                 .long("hm")
-                .help("Apply huffman codec after/before lzw"),
+                .help("Apply huffman codec after/before lzw. Levels 8 and 9 do when it helps"),
+        )
+        .arg(
+            Arg::new("text")
+                .action(ArgAction::SetTrue)
+                .long("text")
+                .help("Encode Unicode characters instead of bytes. Input must be UTF-8"),
+        )
+        .args(level_args())
+        .arg(
+            Arg::new("max-bits")
+                .long("max-bits")
+                .value_parser(clap::value_parser!(u8).range(9..=24))
+                .help("Widest LZW codeword. Bounds the dictionary to 2^N entries. Overrides the level"),
+        )
+        .arg(
+            Arg::new("policy")
+                .long("policy")
+                .value_parser(["freeze", "clear", "adaptive", "lru"])
+                .help("What to do once the dictionary is full: keep it, reset it, \
+                    reset it when the compression ratio drops, or replace least recently used entries. \
+                    Overrides the level"),
+        )
+        .arg(
+            Arg::new("reset-threshold")
+                .long("reset-threshold")
+                .value_parser(clap::value_parser!(u8).range(0..=100))
+                .help("Percent the ratio may fall below its best before an adaptive reset [default: 0]"),
+        )
+        .arg(
+            Arg::new("growth")
+                .long("growth")
+                .value_parser(["classic", "lzmw", "lzap"])
+                .default_value("classic")
+                .help("Entries made from each match: previous match plus one symbol, \
+                    plus the whole current match, or plus each of its prefixes"),
+        )
+        .arg(
+            Arg::new("parsing")
+                .long("parsing")
+                .value_parser(["greedy", "flexible"])
+                .help("Write the longest match, or the one the next match reaches furthest from. \
+                    Decoding is the same either way. Overrides the level"),
+        )
+        .arg(
+            Arg::new("dict")
+                .long("dict")
+                .help("Preset dictionary file to seed the dictionary with. \
+                    Compressing with one uses its mode"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .conflicts_with("stdout")
+                .help("Write to this file instead of the input's name with .lzw added or taken off"),
+        )
+        .arg(
+            Arg::new("stdout")
+                .action(ArgAction::SetTrue)
+                .short('c')
+                .long("stdout")
+                .help("Write to standard output and keep the input"),
+        )
+        .arg(
+            Arg::new("force")
+                .action(ArgAction::SetTrue)
+                .short('f')
+                .long("force")
+                .help("Overwrite existing output files, and write compressed data to a terminal"),
+        )
+        .arg(
+            Arg::new("keep")
+                .action(ArgAction::SetTrue)
+                .short('k')
+                .long("keep")
+                .help("Keep the input file once it has been compressed or decompressed"),
+        )
+        .arg(
+            Arg::new("append")
+                .action(ArgAction::SetTrue)
+                .long("append")
+                .conflicts_with_all(["decompress", "stdout", "test"])
+                .help("Add to the end of an existing output file as a further member. \
+                    Decompressing it gives back each input in turn"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('T')
+                .long("threads")
+                .value_parser(clap::value_parser!(usize))
+                .help("Compress in blocks on this many threads, 0 for one per core. \
+                    Decompressing uses them on files made in blocks [default: 1]"),
+        )
+        .args(limit_args())
+        .arg(
+            Arg::new("block-size")
+                .long("block-size")
+                .value_parser(clap::value_parser!(u32).range(1..=1024 * 1024))
+                .help("Compress in independent blocks of this many KiB, which threads can share \
+                    [default: 1024 with --threads]"),
+        )
+        .arg(
+            Arg::new("test")
+                .action(ArgAction::SetTrue)
+                .short('t')
+                .long("test")
+                .conflicts_with_all(["output", "stdout"])
+                .help("Check that compressed files decode to the size and checksum they record, writing nothing"),
+        )
+        .arg(
+            Arg::new("stats")
+                .action(ArgAction::SetTrue)
+                .long("stats")
+                .conflicts_with_all(["decompress", "test", "threads", "block-size"])
+                .help("Report to standard error what the encoders did: codeword widths, match lengths, \
+                    dictionary fill and resets, and Huffman code lengths against the entropy"),
+        )
+        .arg(
+            Arg::new("range")
+                .long("range")
+                .value_name("OFFSET:LENGTH")
+                .value_parser(parse_range)
+                .requires("decompress")
+                .conflicts_with("test")
+                .help("Decompress only LENGTH bytes from OFFSET, to standard output unless -o is given. \
+                    Of files compressed in blocks, only the blocks it covers are decoded"),
+        )
+        .arg(
+            Arg::new("recursive")
+                .action(ArgAction::SetTrue)
+                .short('r')
+                .long("recursive")
+                .help("Process the files in directories given as input, and in their subdirectories"),
         )
         .arg(
             Arg::new("input")
-                .help("Path to input file")
+                .help("Files to compress or decompress, - for standard input")
+                .num_args(1..)
                 .required(true)
         )
-        .get_matches();
+}
+
+/// `-1` to `-9`, of which only the ends are shown in the help
+fn level_args() -> impl Iterator<Item = Arg> {
+    (1..=9u8).map(|level| {
+        Arg::new(LEVELS[usize::from(level) - 1])
+            .short(char::from(b'0' + level))
+            .action(ArgAction::SetTrue)
+            .group("level")
+            .hide(level != 1 && level != 9)
+            .help(if level == 1 {
+                "Compress faster. Levels go from -1 to -9, -6 is the default"
+            } else {
+                "Compress better"
+            })
+    })
+}
 
+/// `--max-entries`, `--max-output`, `--max-memory` and `--max-work`, which every
+/// command that decodes takes
+fn limit_args() -> [Arg; 4] {
+    [
+        Arg::new("max-entries")
+            .long("max-entries")
+            .value_parser(clap::value_parser!(usize))
+            .help("Fail rather than let the dictionary hold more entries than this"),
+        Arg::new("max-output")
+            .long("max-output")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Fail rather than decompress a file to more than SIZE bytes. \
+                Sizes take a K, M or G suffix"),
+        Arg::new("max-memory")
+            .long("max-memory")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Fail rather than let a dictionary and its output take more than about SIZE bytes, \
+                per thread"),
+        Arg::new("max-work")
+            .long("max-work")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Fail rather than copy more than SIZE symbols into dictionary entries while decoding, \
+                per thread. LZMW and LZAP streams can make that far more than the output"),
+    ]
+}
 
-    // Get handle to input file
-    let mut input_file = File::open(
-        matches.get_one::<String>("input")
-        .expect("Input file argument required")
-    )
-    .expect("File given as input must exist");
+/// The level flag given, or the default
+fn level(matches : &ArgMatches) -> u8 {
+    (1..=9u8)
+        .find(|level| matches.get_flag(LEVELS[usize::from(*level) - 1]))
+        .unwrap_or(DEFAULT_LEVEL)
+}
 
+/// Extension compressed files get
+const SUFFIX: &str = ".lzw";
 
-    // Check if decompress flag was passed
-    if matches.get_flag("decompress") {
-        let decoder = LzwDecoder::new();
+/// Input name that means standard input
+const STDIN: &str = "-";
 
-        let mut buffer = Vec::new();
-        input_file.read_to_end(&mut buffer).unwrap();
-    
-        if matches.get_flag("hm") {
-            buffer = HuffmanDecoder::new().decode(&buffer);
-        }
+fn main() {
+    let matches = cli().get_matches();
+
+    match matches.subcommand() {
+        Some(("train", matches)) => {
+            if let Err(message) = train(matches) {
+                eprintln!("lzw: {message}");
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(("info", matches)) => {
+            if !info(matches) {
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(("dump-dict", matches)) => {
+            if let Err(message) = dump_dict(matches) {
+                eprintln!("lzw: {message}");
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(("bench", matches)) => {
+            if let Err(message) = bench(matches) {
+                eprintln!("lzw: {message}");
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some(("archive", matches)) => {
+            if let Err(message) = archive(matches) {
+                eprintln!("lzw: {message}");
+                std::process::exit(1);
+            }
+            return;
+        },
+        _ => {},
+    }
 
-        let output_cast: Vec<usize> = u8_to_usize(buffer);
+    if !run(&matches) {
+        std::process::exit(1);
+    }
+}
+
+
+/// Where the result of compressing or decompressing goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Destination {
+    Stdout,
+    /// `derived` is set when the name came from the input's, which is then
+    /// removed like gzip does
+    File { path : PathBuf, derived : bool },
+}
 
-        let decompressed = decoder.decode(output_cast);
-        File::create("decompressed.txt").unwrap().write_all(decompressed.as_bytes()).unwrap();
+/// Picks the destination gzip would: standard output for `-c` or standard
+/// input, the `-o` path if given, otherwise the input's name with `SUFFIX`
+/// added when compressing or taken off when decompressing
+fn destination(
+    input : &str,
+    output : Option<&str>,
+    to_stdout : bool,
+    decompressing : bool,
+) -> Result<Destination, String> {
+    if let Some(output) = output {
+        return Ok(Destination::File { path : output.into(), derived : false });
+    }
+    if to_stdout || input == STDIN {
+        return Ok(Destination::Stdout);
+    }
 
+    if decompressing {
+        match input.strip_suffix(SUFFIX) {
+            Some(stem) if !stem.is_empty() && !stem.ends_with(std::path::MAIN_SEPARATOR) => {
+                Ok(Destination::File { path : stem.into(), derived : true })
+            },
+            _ => Err(format!("{input}: unknown suffix, name the output with -o or use -c")),
+        }
+    } else if input.ends_with(SUFFIX) {
+        Err(format!("{input}: already has {SUFFIX} suffix, name the output with -o or use -c"))
     } else {
-        let encoder = LzwEncoder::new();
-        let mut buffer = String::new();
+        Ok(Destination::File { path : format!("{input}{SUFFIX}").into(), derived : true })
+    }
+}
 
-        input_file.read_to_string(&mut buffer).unwrap();
+/// Sizes of one input and what it became
+struct Outcome {
+    name : String,
+    original : usize,
+    compressed : usize,
+}
 
-        buffer = buffer.to_lowercase();
-        let input = buffer.chars()
-        .collect::<Vec<char>>();
+/// Compresses or decompresses every input named on the command line,
+/// carrying on past failures. Returns whether they all succeeded.
+fn run(matches : &ArgMatches) -> bool {
+    let inputs = matches.get_many::<String>("input").unwrap().cloned().collect::<Vec<String>>();
+    let testing = matches.get_flag("test");
+    let decompressing = matches.get_flag("decompress") || testing;
+
+    let preset = match matches.get_one::<String>("dict").map(|path| load_preset(path)).transpose() {
+        Ok(preset) => preset.map(Arc::new),
+        Err(message) => {
+            eprintln!("lzw: {message}");
+            return false;
+        },
+    };
+
+    let mut succeeded = true;
+    let mut outcomes = vec![];
+
+    let (files, errors) = collect_inputs(&inputs, matches.get_flag("recursive"), decompressing);
+    for message in errors.iter() {
+        eprintln!("lzw: {message}");
+        succeeded = false;
+    }
 
-        let lzw_compressed = encoder.encode(input);
+    if files.len() > 1 && matches.contains_id("output") {
+        eprintln!("lzw: -o names a single output, but there are {} inputs", files.len());
+        return false;
+    }
 
-        if matches.get_flag("hm") {
-            // Further compress with Huffman encoding
-            let mut hm_lzw_encoded = HuffmanEncoder::new().encode(&usize_to_u8(&lzw_compressed.bits));
+    if testing {
+        for input in files.iter() {
+            succeeded &= test(input, matches.get_flag("hm"), preset.as_ref(), threads(matches), limits(matches));
+        }
+        return succeeded;
+    }
 
-            File::create("hm_lzw_compressed").unwrap().write_all(&mut hm_lzw_encoded).unwrap();
+    for input in files.iter() {
+        match process(matches, input, preset.clone()) {
+            Ok(outcome) => outcomes.push(outcome),
+            Err(message) => {
+                eprintln!("lzw: {message}");
+                succeeded = false;
+            },
+        }
+    }
 
+    if files.len() > 1 {
+        eprint!("{}", summary(&outcomes, decompressing));
+    }
+    succeeded
+}
+
+/// Expands the inputs into the files to process, walking directories when
+/// `recursive` is set. Files met in directories that already are (or, when
+/// decompressing, aren't) compressed are skipped, as gzip does. Also returns
+/// the inputs that couldn't be expanded.
+fn collect_inputs(inputs : &[String], recursive : bool, decompressing : bool) -> (Vec<String>, Vec<String>) {
+    let mut files = vec![];
+    let mut errors = vec![];
+
+    for input in inputs {
+        let path = Path::new(input);
+        if input == STDIN || !path.is_dir() {
+            files.push(input.clone());
+        } else if !recursive {
+            errors.push(format!("{input}: is a directory, use -r to process its files"));
         } else {
-            lzw_compressed.to_file("lzw_compressed")
+            let mut found = vec![];
+            match walk(path, &mut found) {
+                Ok(()) => files.extend(
+                    found
+                        .into_iter()
+                        .filter_map(|path| path.to_str().map(str::to_string))
+                        .filter(|name| name.ends_with(SUFFIX) == decompressing),
+                ),
+                Err(e) => errors.push(format!("{input}: {e}")),
+            }
         }
     }
+
+    (files, errors)
 }
 
+/// Adds the files under `directory` to `files`, in name order
+fn walk(directory : &Path, files : &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut paths = fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-struct Bits {
-    bits: Vec<IntType>,
-    size: IntType,
+    for path in paths {
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
 }
 
-impl Bits {
-    fn new(val : Vec<IntType>, word_size : IntType) -> Self {
-        let mut bits = val;
+/// Table of sizes and ratios, one row per file and a total, like `gzip -l`
+fn summary(outcomes : &[Outcome], decompressing : bool) -> String {
+    let heading = if decompressing { "decompressed" } else { "compressed" };
+    let mut table = format!("{:>12} {:>12} {:>7}  {heading}\n", "original", "compressed", "ratio");
+
+    for outcome in outcomes {
+        table += &format!(
+            "{:>12} {:>12} {:>7.3}  {}\n",
+            outcome.original,
+            outcome.compressed,
+            ratio(outcome.original, outcome.compressed),
+            outcome.name,
+        );
+    }
+
+    let original = outcomes.iter().map(|outcome| outcome.original).sum::<usize>();
+    let compressed = outcomes.iter().map(|outcome| outcome.compressed).sum::<usize>();
+    table += &format!(
+        "{original:>12} {compressed:>12} {:>7.3}  (totals, {} files)\n",
+        ratio(original, compressed),
+        outcomes.len(),
+    );
+    table
+}
 
-        let available_bits = bits.len() as IntType * INTTYPE_BITS;
+/// Compresses or decompresses one input
+fn process(matches : &ArgMatches, input : &str, preset : Option<Arc<PresetDictionary>>) -> Result<Outcome, String> {
+    let decompressing = matches.get_flag("decompress");
+    let force = matches.get_flag("force");
+    let append = matches.get_flag("append");
+
+    let range = matches.get_one::<(u64, u64)>("range");
+
+    let destination = destination(
+        input,
+        matches.get_one::<String>("output").map(String::as_str),
+        matches.get_flag("stdout") || range.is_some(),
+        decompressing,
+    )?;
+
+    match &destination {
+        Destination::File { path, .. } if path.exists() && append => check_appendable(path)?,
+        Destination::File { path, .. } if path.exists() && !force => {
+            return Err(format!("{}: already exists, use --force to overwrite it", path.display()));
+        },
+        Destination::Stdout if !decompressing && io::stdout().is_terminal() && !force => {
+            return Err("refusing to write compressed data to a terminal, use --force to do it anyway".into());
+        },
+        _ => {},
+    }
 
-        if word_size > available_bits {
-            let pad_size = (word_size - available_bits) / INTTYPE_BITS;
-            let mut padded = vec![0; pad_size as usize];
+    let buffer = read_input(input).map_err(|e| format!("{input}: {e}"))?;
 
-            padded.append(&mut bits);
-            bits = padded;
+    let threads = threads(matches);
+    let output = if let Some((offset, length)) = range {
+        decompress_range(&buffer, matches.get_flag("hm"), preset.as_ref(), *offset, *length, limits(matches))
+    } else if decompressing {
+        // Files with a header record whether huffman was used, older files rely on --hm
+        decompress_parallel(&buffer, matches.get_flag("hm"), preset.as_ref(), threads, limits(matches))
+    } else {
+        let (config, huffman) = compress_settings(matches, preset);
+        match block_size(matches) {
+            Some(block_size) => compress_blocks(&buffer, &config, huffman, block_size, threads),
+            None if matches.get_flag("stats") => compress_with_stats(&buffer, &config, huffman).map(|(output, stats)| {
+                eprint!("{}", stats_report(input, &stats));
+                output
+            }),
+            None => compress(&buffer, &config, huffman),
         }
+    }
+    .map_err(|e| format!("{input}: {e}"))?;
+
+    let name = match &destination {
+        Destination::Stdout => {
+            io::stdout().write_all(&output).map_err(|e| format!("standard output: {e}"))?;
+            input.to_string()
+        },
+        Destination::File { path, derived } => {
+            let written = if append {
+                File::options().create(true).append(true).open(path).and_then(|mut file| file.write_all(&output))
+            } else {
+                fs::write(path, &output)
+            };
+            written.map_err(|e| format!("{}: {e}", path.display()))?;
+            if *derived && !matches.get_flag("keep") {
+                fs::remove_file(input).map_err(|e| format!("{input}: {e}"))?;
+            }
+            path.display().to_string()
+        },
+    };
+
+    let (original, compressed) = if decompressing {
+        (output.len(), buffer.len())
+    } else {
+        (buffer.len(), output.len())
+    };
+    Ok(Outcome { name, original, compressed })
+}
 
-        Bits {
-            bits,
-            size : word_size,
-        }
+/// Checks that a member can be appended to `path`: it has to be empty or end
+/// with a member that records its size, which older versions didn't
+fn check_appendable(path : &Path) -> Result<(), String> {
+    let existing = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if existing.is_empty() {
+        return Ok(());
+    }
+
+    let members = members(&existing).map_err(|e| format!("{}: {e}", path.display()))?;
+    match Header::parse(members[members.len() - 1]) {
+        Ok(Some(header)) if header.payload_size.is_some() => Ok(()),
+        _ => Err(format!("{}: can't be appended to, it was made by an older version", path.display())),
+    }
+}
+
+/// Reads `--range`, byte offset and length as `OFFSET:LENGTH`
+fn parse_range(value : &str) -> Result<(u64, u64), String> {
+    let (offset, length) = value.split_once(':').ok_or("expected OFFSET:LENGTH")?;
+    let number = |n : &str| n.parse::<u64>().map_err(|e| format!("{n:?}: {e}"));
+    Ok((number(offset)?, number(length)?))
+}
+
+/// Reads a size in bytes, optionally followed by K, M or G for powers of 1024
+fn parse_size(value : &str) -> Result<u64, String> {
+    let (digits, shift) = match value.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&value[..at], 10),
+        Some((at, 'M' | 'm')) => (&value[..at], 20),
+        Some((at, 'G' | 'g')) => (&value[..at], 30),
+        _ => (value, 0),
+    };
+    let size = digits.parse::<u64>().map_err(|e| format!("{digits:?}: {e}"))?;
+    size.checked_mul(1 << shift).ok_or_else(|| format!("{value} is too large"))
+}
+
+/// `--max-entries`, `--max-output`, `--max-memory` and `--max-work`
+fn limits(matches : &ArgMatches) -> Limits {
+    Limits {
+        max_entries : matches.get_one::<usize>("max-entries").copied(),
+        max_output : matches.get_one::<u64>("max-output").copied(),
+        max_memory : matches.get_one::<u64>("max-memory").map(|size| usize::try_from(*size).unwrap_or(usize::MAX)),
+        max_work : matches.get_one::<u64>("max-work").copied(),
     }
+}
+
+/// `-T`, with 0 standing for the number of cores
+fn threads(matches : &ArgMatches) -> usize {
+    match matches.get_one::<usize>("threads") {
+        Some(0) => thread::available_parallelism().map_or(1, usize::from),
+        Some(threads) => *threads,
+        None => 1,
+    }
+}
+
+/// Bytes per block if the input is to be compressed in blocks: when asked
+/// for, or when there are threads to share them
+fn block_size(matches : &ArgMatches) -> Option<usize> {
+    const DEFAULT_BLOCK_KIB : usize = 1024;
+
+    match matches.get_one::<u32>("block-size") {
+        Some(kib) => Some(*kib as usize * 1024),
+        None if threads(matches) > 1 => Some(DEFAULT_BLOCK_KIB * 1024),
+        None => None,
+    }
+}
+
+/// Decodes `input` without writing it out, and reports whether it was intact
+fn test(
+    input : &str,
+    legacy_huffman : bool,
+    preset : Option<&Arc<PresetDictionary>>,
+    threads : usize,
+    limits : Limits,
+) -> bool {
+    let buffer = match read_input(input) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("lzw: {input}: {e}");
+            return false;
+        },
+    };
+
+    match decompress_parallel(&buffer, legacy_huffman, preset, threads, limits) {
+        Ok(_) => {
+            println!("{input}: OK");
+            true
+        },
+        Err(e @ (Error::MissingDictionary(_) | Error::WrongDictionary { .. } | Error::LimitExceeded { .. })) => {
+            eprintln!("lzw: {input}: {e}");
+            false
+        },
+        Err(e) => {
+            println!("{input}: corrupt, {e}");
+            false
+        },
+    }
+}
+
+/// Reads all of `input`, standard input if it is `STDIN`
+fn read_input(input : &str) -> io::Result<Vec<u8>> {
+    if input == STDIN {
+        let mut buffer = Vec::new();
+        io::stdin().read_to_end(&mut buffer)?;
+        Ok(buffer)
+    } else {
+        fs::read(input)
+    }
+}
+
+fn load_preset(path : &str) -> Result<PresetDictionary, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    PresetDictionary::parse(&bytes).map_err(|e| format!("{path}: {e}"))
+}
+
+/// The level's settings, with the ones given explicitly taking precedence
+fn compress_settings(matches : &ArgMatches, preset : Option<Arc<PresetDictionary>>) -> (LzwConfig, HuffmanStage) {
+    let mode = if let Some(preset) = &preset {
+        preset.mode()
+    } else if matches.get_flag("text") {
+        Mode::Text
+    } else {
+        Mode::Bytes
+    };
 
-    fn unused(&self) -> IntType {
-        self.bits.len() as IntType * INTTYPE_BITS - self.size
+    let level = level(matches);
+    let mut config = LzwConfig::level(mode, level);
+
+    if let Some(max_bits) = matches.get_one::<u8>("max-bits") {
+        config.max_word_size = (*max_bits).into();
     }
 
-    /// Concatenates two Bits into one Bits, with their combined size. 
-    /// 
-    /// Assumes self is left-adjusted, and other is right-adjusted.
-    fn concat(mut self, mut other : Bits) -> Bits {
-        let combined_size = self.size + other.size;
+    config.full_policy = match matches.get_one::<String>("policy").map(String::as_str) {
+        Some("freeze") => FullPolicy::Freeze,
+        Some("clear") => FullPolicy::Clear,
+        Some("lru") => FullPolicy::Lru,
+        Some(_) => FullPolicy::AdaptiveClear { threshold : 0 },
+        None => config.full_policy,
+    };
+    if let (FullPolicy::AdaptiveClear { .. }, Some(threshold)) =
+        (config.full_policy, matches.get_one::<u8>("reset-threshold"))
+    {
+        config.full_policy = FullPolicy::AdaptiveClear { threshold : *threshold };
+    }
 
-        let remainder_shift = self.unused(); // Space left for other's bits
-        let filler_shift = INTTYPE_BITS - remainder_shift;
+    config.growth = match matches.get_one::<String>("growth").unwrap().as_str() {
+        "lzmw" => Growth::Lzmw,
+        "lzap" => Growth::Lzap,
+        _ => Growth::Classic,
+    };
 
-        other = other.shift_left();
+    config.parsing = match matches.get_one::<String>("parsing").map(String::as_str) {
+        Some("flexible") => Parsing::Flexible,
+        Some(_) => Parsing::Greedy,
+        None => config.parsing,
+    };
+
+    config.preset = preset;
+    config.limits = limits(matches);
+
+    let huffman = if matches.get_flag("hm") {
+        HuffmanStage::On
+    } else {
+        HuffmanStage::level(level)
+    };
+    (config, huffman)
+}
 
-        if self.unused() == 0 {
-            self.bits.append(&mut other.bits);
-            return Bits { bits : self.bits, size : combined_size }
+
+
+/// `lzw train`: picks the phrases worth the most on the samples and reports
+/// how well they compress them
+fn train(matches : &ArgMatches) -> Result<(), String> {
+    let directory = matches.get_one::<String>("samples").unwrap();
+    let output = matches.get_one::<String>("output").unwrap();
+    if Path::new(output).exists() && !matches.get_flag("force") {
+        return Err(format!("{output}: already exists, use --force to overwrite it"));
+    }
+
+    let mut paths = fs::read_dir(directory)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<PathBuf>>>())
+        .map_err(|e| format!("{directory}: {e}"))?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    let samples = paths
+        .iter()
+        .map(|path| fs::read(path).map_err(|e| format!("{}: {e}", path.display())))
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+    let mode = if matches.get_flag("text") {
+        Mode::Text
+    } else {
+        Mode::Bytes
+    };
+
+    let cold = LzwConfig {
+        max_word_size : (*matches.get_one::<u8>("max-bits").unwrap()).into(),
+        ..LzwConfig::new(mode)
+    };
+    let capacity = cold.preset_capacity();
+    let entries = matches.get_one::<usize>("entries").map_or(capacity, |entries| (*entries).min(capacity));
+
+    let preset = PresetDictionary::train(mode, samples.iter().map(Vec::as_slice), entries)
+        .map_err(|e| format!("{directory}: {e}"))?;
+
+    fs::write(output, preset.to_bytes()).map_err(|e| format!("{output}: {e}"))?;
+
+    // What the samples come to with and without the dictionary
+    let warm = LzwConfig { preset : Some(Arc::new(preset)), ..cold.clone() };
+    let original = samples.iter().map(Vec::len).sum::<usize>();
+    let compressed_size = |config : &LzwConfig| {
+        paths
+            .iter()
+            .zip(samples.iter())
+            .map(|(path, sample)| {
+                compress(sample, config, HuffmanStage::Off)
+                    .map(|compressed| compressed.len())
+                    .map_err(|e| format!("{}: {e}", path.display()))
+            })
+            .sum::<Result<usize, String>>()
+    };
+    let with_preset = compressed_size(&warm)?;
+    let without = compressed_size(&cold)?;
+
+    let preset = warm.preset.as_ref().unwrap();
+    println!("{output}: {} phrases from {} samples, id {:08x}", preset.len(), samples.len(), preset.id());
+    println!(
+        "expected ratio {:.3} ({original} -> {with_preset} bytes), {:.3} without it ({without} bytes)",
+        ratio(original, with_preset),
+        ratio(original, without),
+    );
+    Ok(())
+}
+
+/// `lzw info`: describes each file, carrying on past failures
+fn info(matches : &ArgMatches) -> bool {
+    let preset = match matches.get_one::<String>("dict").map(|path| load_preset(path)).transpose() {
+        Ok(preset) => preset.map(Arc::new),
+        Err(message) => {
+            eprintln!("lzw: {message}");
+            return false;
+        },
+    };
+
+    let mut succeeded = true;
+    let mut separator = "";
+    for path in matches.get_many::<String>("files").unwrap() {
+        match describe(path, matches, preset.as_ref()) {
+            Ok(description) => {
+                print!("{separator}{description}");
+                separator = "\n";
+            },
+            Err(message) => {
+                eprintln!("lzw: {path}: {message}");
+                succeeded = false;
+            },
         }
+    }
+    succeeded
+}
 
-        // Shuffle other's bits backwards
-        for i in other.bits {
-            let end = self.bits.last_mut().unwrap();
-            let fill = i >> filler_shift;
-            let remainder = i << remainder_shift;
+/// Describes each member of the file at `path`, the way `describe_member` does
+fn describe(path : &str, matches : &ArgMatches, preset : Option<&Arc<PresetDictionary>>) -> Result<String, String> {
+    let input = fs::read(path).map_err(|e| e.to_string())?;
+    let members = members(&input).map_err(|e| e.to_string())?;
 
-            *end |= fill;
-            self.bits.push(remainder);
+    let mut lines = vec![path.to_string()];
+    if let [member] = members[..] {
+        lines.extend(describe_member(member, matches, preset)?.iter().map(|line| format!("  {line}")));
+    } else {
+        lines.push(format!("  {:<12} {}", "members", members.len()));
+        for (i, member) in members.iter().enumerate() {
+            lines.push(format!("  member {}", i + 1));
+            lines.extend(describe_member(member, matches, preset)?.iter().map(|line| format!("    {line}")));
         }
+    }
+    Ok(lines.iter().map(|line| format!("{line}\n")).collect())
+}
+
+/// Lines of `lzw info` for one member, label then value
+fn describe_member(
+    input : &[u8],
+    matches : &ArgMatches,
+    preset : Option<&Arc<PresetDictionary>>,
+) -> Result<Vec<String>, String> {
+    let legacy_huffman = matches.get_flag("hm");
+    let inspection = inspect(input, legacy_huffman).map_err(|e| e.to_string())?;
+
+    let mut lines = vec![];
+    let mut line = |label : &str, value : String| lines.push(format!("{label:<12} {value}"));
+
+    let huffman = inspection.header.as_ref().map_or(legacy_huffman, |header| header.huffman);
+    let pipeline = match (huffman, inspection.blocks) {
+        (true, Some(_)) => "lzw+huffman where it helps each block",
+        (true, None) => "lzw+huffman",
+        (false, _) => "lzw",
+    };
+    line("pipeline", pipeline.to_string());
+
+    match &inspection.header {
+        Some(header) => {
+            line("format", format!("version {}", header.version()));
+            line("alphabet", alphabet(header.mode));
+            line(
+                "dictionary",
+                format!(
+                    "{}-bit codewords, {} when full, {} growth{}",
+                    header.max_word_size,
+                    policy_name(header.full_policy),
+                    growth_name(header.growth),
+                    header.dictionary_id.map_or(String::new(), |id| format!(", preset {id:08x}")),
+                ),
+            );
+            line(
+                "sizes",
+                format!(
+                    "{} -> {} bytes (header {}, payload {}), ratio {:.3}",
+                    header.original_size,
+                    inspection.compressed_size,
+                    header.size(),
+                    inspection.payload_size,
+                    ratio(header.original_size as usize, inspection.compressed_size),
+                ),
+            );
+            if let Some(blocks) = inspection.blocks {
+                let index = if inspection.indexed { ", indexed for --range" } else { "" };
+                line("blocks", format!("{blocks}, each compressed on its own{index}"));
+            }
+        },
+        None => {
+            line("format", "legacy, no header".to_string());
+            line("alphabet", alphabet(Mode::Legacy));
+            line("sizes", format!("{} bytes compressed, original size not recorded", inspection.compressed_size));
+        },
+    }
+
+    if !matches.get_flag("quick") {
+        let widths = code_widths(input, legacy_huffman, preset, limits(matches)).map_err(|e| e.to_string())?;
+        line("code widths", width_summary(&widths));
+    }
+
+    if let Some(summary) = &inspection.huffman {
+        line(
+            "huffman",
+            format!(
+                "{} byte values, codewords {}-{} bits, {:.2} bits per byte over {} bytes",
+                summary.symbols,
+                summary.shortest,
+                summary.longest,
+                summary.mean_bits,
+                summary.encoded_bytes,
+            ),
+        );
+    }
+
+    let checksum = inspection.header.as_ref().and_then(|header| header.checksum);
+    line("checksum", checksum.map_or("none recorded".to_string(), |crc| format!("crc32 {crc:08x}")));
+
+    Ok(lines)
+}
+
+fn alphabet(mode : Mode) -> String {
+    let mut description = format!("{}, {} symbols", mode.name(), mode.alphabet().len());
+    if mode.has_escape() {
+        description += ", escape";
+    }
+    if mode.has_control_words() {
+        description += ", end-of-stream and clear codewords";
+    }
+    description
+}
+
+fn policy_name(policy : FullPolicy) -> String {
+    match policy {
+        FullPolicy::Freeze => "freeze".to_string(),
+        FullPolicy::Clear => "clear".to_string(),
+        FullPolicy::AdaptiveClear { threshold } => format!("adaptive clear ({threshold}% threshold)"),
+        FullPolicy::Lru => "lru replacement".to_string(),
+    }
+}
 
-        let mut new = Bits { bits : self.bits, size : combined_size };
+fn growth_name(growth : Growth) -> &'static str {
+    match growth {
+        Growth::Classic => "classic",
+        Growth::Lzmw => "lzmw",
+        Growth::Lzap => "lzap",
+    }
+}
+
+/// Range, mean and histogram of codeword widths
+fn width_summary(widths : &[usize]) -> String {
+    let count = widths.iter().sum::<usize>();
+    if count == 0 {
+        return "no codewords".to_string();
+    }
 
-        while new.unused() >= INTTYPE_BITS {
-            new.bits.pop();
+    let used = (0..widths.len()).filter(|width| widths[*width] > 0).collect::<Vec<usize>>();
+    let mean = widths.iter().enumerate().map(|(width, n)| width * n).sum::<usize>() as f64 / count as f64;
+    let histogram = used.iter().map(|width| format!("{width}:{}", widths[*width])).collect::<Vec<String>>();
+
+    format!(
+        "{count} codewords, {}-{} bits, mean {mean:.2} ({})",
+        used[0],
+        used[used.len() - 1],
+        histogram.join(" "),
+    )
+}
+
+/// `--stats` report for one input, label then value like `lzw info`
+fn stats_report(name : &str, stats : &CompressionStats) -> String {
+    let lzw = &stats.lzw;
+    let symbols = lzw.fill.last().map_or(0, |(at, _)| *at);
+
+    let mut lines = vec![name.to_string()];
+    let mut line = |label : &str, value : String| lines.push(format!("  {label:<12} {value}"));
+
+    line(
+        "lzw",
+        format!(
+            "{symbols} symbols -> {} bytes, {:.2} bits per symbol",
+            stats.lzw_size,
+            (stats.lzw_size * 8) as f64 / symbols.max(1) as f64,
+        ),
+    );
+    line("code widths", width_summary(&lzw.widths));
+    line("matches", match_summary(&lzw.match_lengths));
+    if lzw.escapes > 0 {
+        line("escapes", format!("{} symbols sent raw", lzw.escapes));
+    }
+    line("fill", fill_summary(&lzw.fill, lzw.capacity));
+    line("resets", reset_summary(&lzw.resets));
+
+    if let Some(huffman) = &stats.huffman {
+        let kept = if stats.huffman_kept { "kept" } else { "dropped, it came out larger" };
+        line(
+            "huffman",
+            format!(
+                "{:.2} bits per byte against an entropy of {:.2}, {kept}",
+                huffman.mean_bits,
+                huffman.entropy,
+            ),
+        );
+        let mut lengths = vec![];
+        for (_, length) in &huffman.code_lengths {
+            if lengths.len() <= *length {
+                lengths.resize(length + 1, 0);
+            }
+            lengths[*length] += 1;
         }
-        new
-    }
-
-    /// Shifts from the right to the left
-    fn shift_left(mut self) -> Bits {
-        let difference = self.unused();
-    
-        let mut new_bits = vec![];
-    
-        self.bits.push(0);
-        for w in self.bits.windows(2) {
-            let mut a = w[0];
-            let mut b = w[1];
-    
-            a <<= difference;
-            b >>= INTTYPE_BITS - difference;
-            new_bits.push(a | b);
+        let histogram = (0..lengths.len())
+            .filter(|length| lengths[*length] > 0)
+            .map(|length| format!("{length}:{}", lengths[length]))
+            .collect::<Vec<String>>();
+        line(
+            "code lengths",
+            format!("{} byte values by codeword bits ({})", huffman.code_lengths.len(), histogram.join(" ")),
+        );
+    }
+
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// Count, mean and longest match, with a histogram in powers of two
+fn match_summary(lengths : &[usize]) -> String {
+    let count = lengths.iter().sum::<usize>();
+    if count == 0 {
+        return "no matches".to_string();
+    }
+
+    let mean = lengths.iter().enumerate().map(|(length, n)| length * n).sum::<usize>() as f64 / count as f64;
+    let mut buckets = vec![];
+    let mut low = 1;
+    while low < lengths.len() {
+        let high = (low * 2 - 1).min(lengths.len() - 1);
+        let n = lengths[low..=high].iter().sum::<usize>();
+        if n > 0 {
+            let range = if low == high { low.to_string() } else { format!("{low}-{high}") };
+            buckets.push(format!("{range}:{n}"));
         }
-    
-        Bits {
-            bits : new_bits,
-            size : self.size
+        low *= 2;
+    }
+
+    format!("{count}, mean {mean:.2} symbols, longest {} ({})", lengths.len() - 1, buckets.join(" "))
+}
+
+/// Codewords taken at each tenth of the input
+fn fill_summary(fill : &[(usize, usize)], capacity : usize) -> String {
+    let Some((symbols, _)) = fill.last().copied() else {
+        return "no input".to_string();
+    };
+
+    let points = (1..=10)
+        .map(|tenth| {
+            let at = symbols * tenth / 10;
+            let taken = fill.iter().rev().find(|(position, _)| *position <= at).map_or(0, |(_, taken)| *taken);
+            format!("{}%:{taken}", tenth * 10)
+        })
+        .collect::<Vec<String>>();
+    format!("{} of {capacity} codewords, at each tenth of the input", points.join(" "))
+}
+
+/// Number of resets and where the first few were
+fn reset_summary(resets : &[usize]) -> String {
+    const SHOWN : usize = 8;
+
+    if resets.is_empty() {
+        return "none".to_string();
+    }
+    let mut positions = resets.iter().take(SHOWN).map(usize::to_string).collect::<Vec<String>>();
+    if resets.len() > SHOWN {
+        positions.push("...".to_string());
+    }
+    format!("{}, after {} symbols", resets.len(), positions.join(" "))
+}
+
+/// `lzw dump-dict`: writes out the dictionary the first member of a file
+/// ends with, or that of one of its blocks
+fn dump_dict(matches : &ArgMatches) -> Result<(), String> {
+    let path = matches.get_one::<String>("file").unwrap();
+    let preset = matches.get_one::<String>("dict").map(|path| load_preset(path)).transpose()?.map(Arc::new);
+
+    let input = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let mut dumps = dictionaries(&input, matches.get_flag("hm"), preset.as_ref(), limits(matches))
+        .map_err(|e| format!("{path}: {e}"))?;
+
+    let block = *matches.get_one::<u32>("block").unwrap() as usize;
+    if block > dumps.len() {
+        return Err(format!("{path}: there is no block {block}, only {}", dumps.len()));
+    }
+    let dump = dumps.swap_remove(block - 1);
+
+    let text = match matches.get_one::<String>("format").unwrap().as_str() {
+        "dot" => dump.to_dot(),
+        _ => dump.to_json(),
+    };
+    match matches.get_one::<String>("output") {
+        Some(output) => fs::write(output, text).map_err(|e| format!("{output}: {e}")),
+        None => io::stdout().write_all(text.as_bytes()).map_err(|e| format!("standard output: {e}")),
+    }
+}
+
+/// `lzw bench`: prints a table as each codec finishes, and writes the
+/// reports asked for. Fails if any file didn't round trip.
+fn bench(matches : &ArgMatches) -> Result<(), String> {
+    let directory = Path::new(matches.get_one::<String>("directory").unwrap());
+
+    let mut paths = vec![];
+    walk(directory, &mut paths).map_err(|e| format!("{}: {e}", directory.display()))?;
+    let files = paths
+        .into_iter()
+        .map(|path| fs::read(&path).map(|contents| (path.clone(), contents)).map_err(|e| format!("{}: {e}", path.display())))
+        .collect::<Result<Vec<(PathBuf, Vec<u8>)>, String>>()?;
+
+    let original = files.iter().map(|(_, contents)| contents.len()).sum::<usize>();
+    println!("{}: {} files, {original} bytes", directory.display(), files.len());
+
+    let filter = matches.get_one::<String>("filter");
+    let codecs = bench::codecs()
+        .into_iter()
+        .filter(|codec| filter.is_none_or(|filter| codec.name.contains(filter.as_str())))
+        .collect::<Vec<bench::Codec>>();
+
+    print!("{}", bench::table_heading());
+    let mut rows = vec![];
+    for codec in codecs.iter() {
+        let row = bench::run(codec, &files);
+        print!("{}", bench::table_row(&row));
+        for failure in row.failures.iter() {
+            eprintln!("lzw: {}: {failure}", row.codec);
         }
+        rows.push(row);
     }
 
-    /// Creates a file or truncates an existing one of name `filename`,
-    /// then writes the Bits object *as bits* to the file
-    fn to_file(self, filename : &str) {
-        let mut newfile = File::create(filename).expect("couldn't create file");
+    if let Some(path) = matches.get_one::<String>("csv") {
+        fs::write(path, bench::csv(&rows)).map_err(|e| format!("{path}: {e}"))?;
+    }
+    if let Some(path) = matches.get_one::<String>("json") {
+        fs::write(path, bench::json(directory, &rows)).map_err(|e| format!("{path}: {e}"))?;
+    }
 
-        let bytes = &usize_to_u8(&self.bits[..]);
+    let failures = rows.iter().map(|row| row.failures.len()).sum::<usize>();
+    if failures > 0 {
+        Err(format!("{failures} round trips failed"))
+    } else {
+        Ok(())
+    }
+}
 
-        newfile.write_all(bytes).expect("failed to write entire buffer");
+/// `lzw archive`: dispatches to create, list or extract
+fn archive(matches : &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        Some(("create", matches)) => archive_create(matches),
+        Some(("list", matches)) => archive_list(matches),
+        Some(("extract", matches)) => archive_extract(matches),
+        _ => unreachable!("clap requires a subcommand"),
     }
+}
 
-    // Push a single bit onto a right adjusted Bits
-    fn push_from_left(&mut self, value : IntType) {
-        if self.size % INTTYPE_BITS == 0 {
-            let mut front_bit = vec![0];
-            std::mem::swap(&mut self.bits, &mut front_bit);
-            self.bits.extend(front_bit);
+fn archive_create(matches : &ArgMatches) -> Result<(), String> {
+    let output = Path::new(matches.get_one::<String>("archive").unwrap());
+    if output.exists() && !matches.get_flag("force") {
+        return Err(format!("{}: already exists, use --force to overwrite it", output.display()));
+    }
+
+    let mut files = vec![];
+    for path in matches.get_many::<String>("paths").unwrap().map(PathBuf::from) {
+        if path.is_dir() {
+            walk(&path, &mut files).map_err(|e| format!("{}: {e}", path.display()))?;
+        } else {
+            files.push(path);
         }
+    }
+    // Rewriting an archive in a directory being archived shouldn't take in
+    // its old self, whichever way the two paths spell it
+    if let Ok(output) = fs::canonicalize(output) {
+        files.retain(|path| fs::canonicalize(path).map_or(true, |path| path != output));
+    }
+
+    let mode = if matches.get_flag("text") { Mode::Text } else { Mode::Bytes };
+    let level = level(matches);
+    let huffman = if matches.get_flag("hm") { HuffmanStage::On } else { HuffmanStage::level(level) };
+    let mut writer = ArchiveWriter::new(LzwConfig::level(mode, level), huffman);
 
-        let shift = self.size % INTTYPE_BITS;
-        let mask = value << shift;
-        self.bits[0] |= mask;
+    for path in files {
+        let name = entry_name(&path)?;
+        let contents = fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let metadata = fs::metadata(&path).map_err(|e| format!("{}: {e}", path.display()))?;
 
-        self.size+=1;
+        writer
+            .add(&name, permissions(&metadata), mtime(&metadata), &contents)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
     }
+
+    let count = writer.entries().len();
+    let original = writer.entries().iter().map(|entry| entry.size as usize).sum::<usize>();
+    let bytes = writer.finish();
+    fs::write(output, &bytes).map_err(|e| format!("{}: {e}", output.display()))?;
+
+    eprintln!(
+        "{}: {count} entries, {original} -> {} bytes, ratio {:.3}",
+        output.display(),
+        bytes.len(),
+        ratio(original, bytes.len()),
+    );
+    Ok(())
 }
 
+fn archive_list(matches : &ArgMatches) -> Result<(), String> {
+    let path = matches.get_one::<String>("archive").unwrap();
+    let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let archive = Archive::parse(&bytes).map_err(|e| format!("{path}: {e}"))?;
+
+    println!("{:<10} {:>12} {:>12} {:<12} {:<20} path", "mode", "size", "stored", "pipeline", "modified");
+    for entry in archive.entries() {
+        println!(
+            "{:<10} {:>12} {:>12} {:<12} {:<20} {}",
+            permission_string(entry.permissions),
+            entry.size,
+            entry.stored_size,
+            entry.pipeline.name(),
+            utc_time(entry.mtime),
+            entry.path,
+        );
+    }
 
-fn usize_to_u8(i : &[usize]) -> Vec<u8> {
-    i.into_iter()
-        .flat_map(
-            |i|
-                bytemuck::cast::<usize, [u8; 8]>(*i).into_iter().rev()
-            )
-        .collect::<Vec<u8>>()
+    let original = archive.entries().iter().map(|entry| entry.size as usize).sum::<usize>();
+    println!(
+        "{:<10} {original:>12} {:>12} ratio {:.3}, {} entries",
+        "",
+        bytes.len(),
+        ratio(original, bytes.len()),
+        archive.entries().len(),
+    );
+    Ok(())
 }
 
+/// Extracts what it can, reporting each entry that fails
+fn archive_extract(matches : &ArgMatches) -> Result<(), String> {
+    let path = matches.get_one::<String>("archive").unwrap();
+    let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let archive = Archive::parse(&bytes).map_err(|e| format!("{path}: {e}"))?;
 
-fn u8_to_usize(mut i : Vec<u8>) -> Vec<usize> {
-    let ratio = INTTYPE_BITS / 8; 
-    let disalignment = (ratio - (i.len() % ratio)) % ratio;
-    i.extend(vec![0; disalignment]);
+    let directory = Path::new(matches.get_one::<String>("directory").unwrap());
+    let wanted = matches.get_many::<String>("paths").map(|paths| paths.cloned().collect::<Vec<String>>());
 
-    let (mut segment, mut remainder) = i.split_at(ratio);
-    let mut new_vec : Vec<u8> = vec![];
-    new_vec.extend(segment.iter().rev());
+    let mut failures = 0;
+    if let Some(wanted) = &wanted {
+        for name in wanted.iter().filter(|name| !archive.entries().iter().any(|entry| entry.path == **name)) {
+            eprintln!("lzw: {name}: not in {path}");
+            failures += 1;
+        }
+    }
 
-    while remainder.len() > 0 {
-        (segment, remainder) = remainder.split_at(ratio);
-        new_vec.extend(segment.iter().rev());
+    for entry in archive.entries() {
+        if wanted.as_ref().is_some_and(|wanted| !wanted.contains(&entry.path)) {
+            continue;
+        }
+        if let Err(message) = extract_entry(&archive, entry, directory, matches.get_flag("force"), limits(matches)) {
+            eprintln!("lzw: {}: {message}", entry.path);
+            failures += 1;
+        }
     }
 
-    bytemuck::cast_slice::<u8, usize>(&new_vec[..]).to_vec()
+    if failures > 0 {
+        Err(format!("{path}: {failures} entries could not be extracted"))
+    } else {
+        Ok(())
+    }
 }
 
+fn extract_entry(
+    archive : &Archive,
+    entry : &Entry,
+    directory : &Path,
+    force : bool,
+    limits : Limits,
+) -> Result<(), String> {
+    let target = directory.join(&entry.path);
+    if target.exists() && !force {
+        return Err(format!("{} already exists, use --force to overwrite it", target.display()));
+    }
 
+    let contents = archive.extract(entry, None, limits).map_err(|e| e.to_string())?;
 
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut file = File::create(&target).map_err(|e| e.to_string())?;
+    file.write_all(&contents).map_err(|e| e.to_string())?;
 
+    let modified = if entry.mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(entry.mtime.unsigned_abs())
+    } else {
+        UNIX_EPOCH - Duration::from_secs(entry.mtime.unsigned_abs())
+    };
+    file.set_modified(modified).map_err(|e| e.to_string())?;
+    set_permissions(&target, entry.permissions).map_err(|e| e.to_string())
+}
 
-#[cfg(test)]
-mod bits_test {
-    use crate::Bits;
+/// Path inside the archive: relative, `/` separated, without `.` components
+fn entry_name(path : &Path) -> Result<String, String> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                components.push(name.to_str().ok_or_else(|| format!("{}: name is not UTF-8", path.display()))?)
+            },
+            Component::ParentDir => return Err(format!("{}: paths with .. can't be archived", path.display())),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+        }
+    }
+    Ok(components.join("/"))
+}
 
-    #[test]
-    fn concat_test() {
-        let input1 = Bits {
-            bits : vec![
-                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
-                ],
-                size : 1,
-            };
-        let input2 = Bits { bits : vec![1], size : 1};
+/// Permission bits archives keep. Setuid, setgid and sticky bits are left
+/// out, so extracting an archive can't make a setuid file.
+const PERMISSION_BITS: u32 = 0o777;
 
-        let target = Bits {
-            bits : vec![
-                0b11000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
-                ],
-                size : 2,
-            };
+#[cfg(unix)]
+fn permissions(metadata : &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & PERMISSION_BITS
+}
 
-        assert_eq!(input1.concat(input2), target);
+#[cfg(not(unix))]
+fn permissions(metadata : &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
 
-        let input1 = Bits {
-            bits : vec![
-                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
-                ],
-                size : 1,
-            };
-        let input2 = Bits { bits : vec![
-            0b00000000_00000000_00000000_00000000_00000000_00000000_00000000_00000001,
-            0b00000000_00000000_00000000_00000000_00000000_00000000_00000000_00000001,
-        ], size : 65};
-
-        let target = Bits {
-            bits : vec![
-                0b11000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000,
-                0b01000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000,
-                ],
-                size : 66,
-            };
+#[cfg(unix)]
+fn set_permissions(path : &Path, permissions : u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(permissions & PERMISSION_BITS))
+}
 
-        assert_eq!(input1.concat(input2), target);
+#[cfg(not(unix))]
+fn set_permissions(path : &Path, permissions : u32) -> io::Result<()> {
+    let mut current = fs::metadata(path)?.permissions();
+    current.set_readonly(permissions & 0o222 == 0);
+    fs::set_permissions(path, current)
+}
 
+/// Seconds since the Unix epoch, negative before it
+fn mtime(metadata : &fs::Metadata) -> i64 {
+    match metadata.modified().map(|time| time.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(after)) => after.as_secs() as i64,
+        Ok(Err(before)) => -(before.duration().as_secs() as i64),
+        Err(_) => 0,
     }
+}
 
+/// `rwxr-xr-x` style
+fn permission_string(permissions : u32) -> String {
+    (0..9)
+        .map(|bit| {
+            if permissions & (0o400 >> bit) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][bit % 3]
+            }
+        })
+        .collect()
+}
+
+/// `YYYY-MM-DD hh:mm:ss` in UTC
+fn utc_time(seconds : i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let time = seconds.rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// Original size over compressed size
+fn ratio(original : usize, compressed : usize) -> f64 {
+    original as f64 / compressed.max(1) as f64
+}
+
+
+#[cfg(test)]
+mod cli_test {
+    use crate::*;
 
     #[test]
-    fn left_shift_test() {
-        let input = Bits { bits : vec![1], size : 1};
-        let target = Bits {
-            bits : vec![
-                0b10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000
-            ],
-            size : 1,
-        };
-    
-        assert_eq!(input.shift_left(), target);
+    fn names_follow_gzip() {
+        assert_eq!(
+            destination("notes.txt", None, false, false),
+            Ok(Destination::File { path : "notes.txt.lzw".into(), derived : true })
+        );
+        assert_eq!(
+            destination("notes.txt.lzw", None, false, true),
+            Ok(Destination::File { path : "notes.txt".into(), derived : true })
+        );
+        assert!(destination("notes.txt.lzw", None, false, false).is_err());
+        assert!(destination("notes.txt", None, false, true).is_err());
+        assert!(destination(".lzw", None, false, true).is_err());
+    }
 
+    #[test]
+    fn output_and_stdout_override_name() {
+        assert_eq!(
+            destination("notes.txt", Some("out"), false, true),
+            Ok(Destination::File { path : "out".into(), derived : false })
+        );
+        assert_eq!(destination("notes.txt", None, true, false), Ok(Destination::Stdout));
+        assert_eq!(destination(STDIN, None, false, true), Ok(Destination::Stdout));
     }
 
-}
+    #[test]
+    fn recursion_skips_files_already_done() {
+        let root = std::env::temp_dir().join(format!("lzw-cli-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        for name in ["b.txt", "a.txt.lzw", "nested/c.txt"] {
+            fs::write(root.join(name), "x").unwrap();
+        }
+        let root_name = root.to_str().unwrap().to_string();
+        let inputs = [root_name.clone()];
+        let under_root = |files : Vec<String>| {
+            files.iter().map(|file| file[root_name.len() + 1..].to_string()).collect::<Vec<String>>()
+        };
 
+        let (files, errors) = collect_inputs(&inputs, false, false);
+        assert!(files.is_empty() && errors.len() == 1);
+        assert_eq!(under_root(collect_inputs(&inputs, true, false).0), ["b.txt", "nested/c.txt"]);
+        assert_eq!(under_root(collect_inputs(&inputs, true, true).0), ["a.txt.lzw"]);
 
+        fs::remove_dir_all(root).unwrap();
+    }
 
+    #[test]
+    fn summary_totals_sizes() {
+        let outcomes = [
+            Outcome { name : "a.lzw".into(), original : 300, compressed : 100 },
+            Outcome { name : "b.lzw".into(), original : 100, compressed : 100 },
+        ];
+        let table = summary(&outcomes, false);
+
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.lines().last().unwrap().contains("2.000  (totals, 2 files)"));
+    }
 
+    #[test]
+    fn only_members_that_record_their_size_are_appended_to() {
+        let root = std::env::temp_dir().join(format!("lzw-append-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let config = LzwConfig::new(Mode::Bytes);
+
+        let current = root.join("current.lzw");
+        fs::write(&current, compress(b"one", &config, HuffmanStage::Off).unwrap()).unwrap();
+        let empty = root.join("empty.lzw");
+        fs::write(&empty, b"").unwrap();
+        let legacy = root.join("legacy.lzw");
+        fs::write(&legacy, b"\x01\x02\x03").unwrap();
+
+        assert_eq!(check_appendable(&current), Ok(()));
+        assert_eq!(check_appendable(&empty), Ok(()));
+        assert!(check_appendable(&legacy).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn train_reports_errors_and_keeps_existing_output() {
+        let root = std::env::temp_dir().join(format!("lzw-train-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("samples")).unwrap();
+        fs::write(root.join("samples/a.txt"), "Det var en gang en fattigmann").unwrap();
+        let output = root.join("out.lzwd");
+        fs::write(&output, "keep").unwrap();
+        let train_with = |samples : &str, extra : &[&str]| {
+            let samples = root.join(samples);
+            let mut args = vec!["lzw", "train", samples.to_str().unwrap(), "-o", output.to_str().unwrap()];
+            args.extend(extra);
+            let matches = cli().try_get_matches_from(args).unwrap();
+            train(matches.subcommand_matches("train").unwrap())
+        };
 
+        assert!(train_with("samples", &[]).unwrap_err().contains("already exists"));
+        assert_eq!(fs::read(&output).unwrap(), b"keep");
+        assert!(train_with("missing", &["--force"]).unwrap_err().contains("missing"));
 
-#[cfg(target_arch="x86_64")]
-#[cfg(test)]
-mod casting_tests {
-    use crate::*;
+        fs::write(root.join("samples/b.bin"), [0xFF, 0xFE]).unwrap();
+        assert!(train_with("samples", &["--force", "--text"]).is_err());
+
+        assert_eq!(train_with("samples", &["--force"]), Ok(()));
+        assert!(PresetDictionary::parse(&fs::read(&output).unwrap()).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
 
+    #[test]
+    fn archive_names_and_metadata() {
+        assert_eq!(entry_name(Path::new("./docs/notes.txt")), Ok("docs/notes.txt".to_string()));
+        assert_eq!(entry_name(Path::new("/etc/hosts")), Ok("etc/hosts".to_string()));
+        assert!(entry_name(Path::new("../notes.txt")).is_err());
+
+        assert_eq!(permission_string(0o754), "rwxr-xr--");
+        assert_eq!(utc_time(0), "1970-01-01 00:00:00");
+        assert_eq!(utc_time(1_700_000_000), "2023-11-14 22:13:20");
+        assert_eq!(utc_time(-1), "1969-12-31 23:59:59");
+        assert_eq!(utc_time(951_782_400), "2000-02-29 00:00:00");
+    }
 
     #[test]
-    fn u8_to_usize_test() {
-        let inp : &[u8] = &[0b0000_0001, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_0111, 0b0000_0000, 0b0000_1111, 0b0000_0000];
-        let output = u8_to_usize(inp.to_vec());
-        let target  = &[0b0000_0001_0000_0000_0000_0011_0000_0000_0000_0111_0000_0000_0000_1111_0000_0000usize];
+    fn archives_leave_out_themselves_and_special_permissions() {
+        let root = std::env::temp_dir().join(format!("lzw-archive-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("files/nested")).unwrap();
+        fs::write(root.join("files/a.txt"), "Det var en gang").unwrap();
+        fs::write(root.join("files/old.lzwa"), "an archive").unwrap();
+
+        // The archive, named through a detour, inside the directory being archived
+        let (output, files) = (root.join("files/nested/../old.lzwa"), root.join("files"));
+        let args = ["lzw", "archive", "create", "-f", output.to_str().unwrap(), files.to_str().unwrap()];
+        let matches = cli().try_get_matches_from(args).unwrap();
+        archive(matches.subcommand_matches("archive").unwrap()).unwrap();
+
+        let bytes = fs::read(&output).unwrap();
+        let archive = Archive::parse(&bytes).unwrap();
+        let names = archive.entries().iter().map(|entry| entry.path.as_str()).collect::<Vec<&str>>();
+        assert!(names.len() == 1 && names[0].ends_with("files/a.txt"), "{names:?}");
+
+        let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Bytes), HuffmanStage::Off);
+        writer.add("setuid", 0o4755, 0, b"#!/bin/sh").unwrap();
+        let bytes = writer.finish();
+        let archive = Archive::parse(&bytes).unwrap();
+        extract_entry(&archive, &archive.entries()[0], &root, false, Limits::none()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(root.join("setuid")).unwrap().permissions().mode() & 0o7777, 0o755);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
 
-        assert_eq!(*target, *output);
+    #[test]
+    fn every_command_that_decodes_keeps_to_the_limits() {
+        let root = std::env::temp_dir().join(format!("lzw-limits-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let folktale = include_bytes!("../folktale.txt");
+        let compressed = root.join("folktale.txt.lzw");
+        fs::write(&compressed, compress(folktale, &LzwConfig::new(Mode::Bytes), HuffmanStage::Off).unwrap()).unwrap();
+        let mut writer = ArchiveWriter::new(LzwConfig::new(Mode::Bytes), HuffmanStage::Off);
+        writer.add("folktale.txt", 0o644, 0, folktale).unwrap();
+        let archived = root.join("folktale.lzwa");
+        fs::write(&archived, writer.finish()).unwrap();
+        let (compressed, archived) = (compressed.to_str().unwrap(), archived.to_str().unwrap());
+
+        let run = |args : &[&str]| {
+            let matches = cli().try_get_matches_from(args).unwrap();
+            match matches.subcommand() {
+                Some(("info", matches)) => matches.get_many::<String>("files").unwrap().all(|path| {
+                    describe(path, matches, None).is_ok()
+                }),
+                Some(("dump-dict", matches)) => dump_dict(matches).is_ok(),
+                Some(("archive", matches)) => archive(matches).is_ok(),
+                _ => unreachable!(),
+            }
+        };
+        let dump = root.join("dump.json");
+        let extracted = root.join("out");
+        let commands : [Vec<&str>; 3] = [
+            vec!["lzw", "info", compressed],
+            vec!["lzw", "dump-dict", compressed, "-o", dump.to_str().unwrap()],
+            vec!["lzw", "archive", "extract", "-f", archived, "-C", extracted.to_str().unwrap()],
+        ];
+        for command in commands {
+            for limit in [["--max-entries", "300"], ["--max-output", "1K"], ["--max-memory", "4K"]] {
+                assert!(!run(&[&command[..], &limit].concat()), "{command:?} {limit:?}");
+            }
+            assert!(run(&command), "{command:?}");
+        }
+        fs::remove_dir_all(&root).unwrap();
     }
 
     #[test]
-    fn usize_to_u8_test() {
-        let inp = [0b0000_0001_0000_0000_0000_0011_0000_0000_0000_0011_0000_0000_0000_1001_0000_0000usize];
-        let output = usize_to_u8(&inp);
-        let target : &[u8] = &[0b0000_0001, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_0011, 0b0000_0000, 0b0000_1001, 0b0000_0000];
+    fn width_summary_reports_range_and_mean() {
+        assert_eq!(width_summary(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 1]), "4 codewords, 9-10 bits, mean 9.25 (9:3 10:1)");
+        assert_eq!(width_summary(&[]), "no codewords");
+    }
 
-        assert_eq!(target, &output);
+    #[test]
+    fn stats_summaries() {
+        assert_eq!(match_summary(&[0, 4, 2, 1, 0, 0, 0, 1]), "8, mean 2.25 symbols, longest 7 (1:4 2-3:3 4-7:1)");
+        assert_eq!(match_summary(&[]), "no matches");
+        assert_eq!(
+            fill_summary(&[(0, 3), (5, 6), (10, 8)], 8),
+            "10%:3 20%:3 30%:3 40%:3 50%:6 60%:6 70%:6 80%:6 90%:6 100%:8 of 8 codewords, at each tenth of the input",
+        );
+        assert_eq!(reset_summary(&[100, 250]), "2, after 100 250 symbols");
+        assert_eq!(reset_summary(&(1..=9).collect::<Vec<usize>>()), "9, after 1 2 3 4 5 6 7 8 ... symbols");
+
+        let (_, stats) = compress_with_stats(b"abababab", &LzwConfig::new(Mode::Bytes), HuffmanStage::On).unwrap();
+        let report = stats_report("ab.txt", &stats);
+        assert!(report.starts_with("ab.txt\n  lzw          8 symbols -> "), "{report}");
+        assert!(report.contains("  resets       none\n"), "{report}");
+        assert!(report.contains("  code lengths "), "{report}");
+
+        assert!(cli().try_get_matches_from(["lzw", "--stats", "-T", "2", "a.txt"]).is_err());
+        assert!(cli().try_get_matches_from(["lzw", "--stats", "-d", "a.lzw"]).is_err());
     }
 
+    #[test]
+    fn ranges_are_offset_and_length() {
+        assert_eq!(parse_range("1024:10"), Ok((1024, 10)));
+        assert!(parse_range("1024").is_err());
+        assert!(parse_range("-1:10").is_err());
+
+        let matches = cli().try_get_matches_from(["lzw", "-d", "--range", "5:3", "a.lzw"]).unwrap();
+        assert_eq!(matches.get_one::<(u64, u64)>("range"), Some(&(5, 3)));
+        assert!(cli().try_get_matches_from(["lzw", "--range", "5:3", "a.lzw"]).is_err());
+    }
 
+    #[test]
+    fn sizes_take_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("3m"), Ok(3 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn command_is_consistent() {
+        cli().debug_assert();
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::checksum::crc32;
+use crate::error::Error;
+use crate::lzw::Mode;
+
+/// First bytes of every serialised preset dictionary
+const MAGIC: [u8; 4] = *b"LZWD";
+
+const VERSION: u8 = 1;
+
+
+/// Phrases the encoder and decoder seed their dictionaries with, after the
+/// alphabet and the reserved codewords, so short inputs start out warm.
+///
+/// Holds every prefix of its phrases too, shortest first, since greedy
+/// matching only reaches a phrase through its prefixes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresetDictionary {
+    mode: Mode,
+    phrases: Vec<Vec<char>>,
+}
+
+impl PresetDictionary {
+    /// Collects `phrases` and their prefixes, leaving out single symbols
+    /// the alphabet already has.
+    ///
+    /// Fails for `Mode::Legacy`, whose streams have no header to name the
+    /// dictionary in, and for symbols `mode` can't encode.
+    pub fn new(mode : Mode, phrases : impl IntoIterator<Item = Vec<char>>) -> Result<Self, Error> {
+        if mode == Mode::Legacy {
+            return Err(Error::BadDictionary("legacy streams can't use one"));
+        }
+
+        let alphabet = mode.alphabet().into_iter().collect::<BTreeSet<char>>();
+        let mut closed = BTreeSet::new();
+
+        for phrase in phrases {
+            if let Some(symbol) = phrase.iter().find(|c| !mode.can_encode(**c)) {
+                return Err(Error::UnknownSymbol(*symbol));
+            }
+
+            for length in 1..=phrase.len() {
+                let prefix = &phrase[..length];
+                if length == 1 && alphabet.contains(&prefix[0]) {
+                    continue;
+                }
+                closed.insert(prefix.to_vec());
+            }
+        }
+
+        let mut phrases = closed.into_iter().collect::<Vec<Vec<char>>>();
+        phrases.sort_by_key(|phrase| phrase.len());
+
+        Ok(PresetDictionary { mode, phrases })
+    }
+
+    /// Picks the phrases that would have saved the most codewords on
+    /// `samples`, at most `max_entries` of them prefixes included.
+    ///
+    /// Candidates are the phrases LZW learns reading all samples in a row,
+    /// valued by how often a greedy parse over that dictionary uses them.
+    pub fn train<'a>(
+        mode : Mode,
+        samples : impl IntoIterator<Item = &'a [u8]>,
+        max_entries : usize,
+    ) -> Result<Self, Error> {
+        let alphabet = mode.alphabet().into_iter().collect::<BTreeSet<char>>();
+
+        let mut samples_symbols = vec![];
+        for sample in samples {
+            samples_symbols.push(mode.symbols(sample)?);
+        }
+
+        // Phrase -> number of times the greedy parse used it
+        let mut uses = alphabet.iter().map(|c| (vec![*c], 0)).collect::<BTreeMap<Vec<char>, usize>>();
+
+        for symbols in samples_symbols.iter() {
+            let mut sequence = vec![];
+            for &symbol in symbols {
+                uses.entry(vec![symbol]).or_default();
+                sequence.push(symbol);
+                if !uses.contains_key(&sequence) {
+                    uses.insert(sequence.clone(), 0);
+                    sequence = vec![symbol];
+                }
+            }
+        }
+
+        for symbols in samples_symbols.iter() {
+            let mut sequence : Vec<char> = vec![];
+            for &symbol in symbols {
+                sequence.push(symbol);
+                if !uses.contains_key(&sequence) {
+                    sequence.pop();
+                    *uses.get_mut(&sequence).unwrap() += 1;
+                    sequence = vec![symbol];
+                }
+            }
+            if !sequence.is_empty() {
+                *uses.get_mut(&sequence).unwrap() += 1;
+            }
+        }
+
+        // Every use of a phrase replaces one codeword per symbol but the
+        // first. Symbols outside the alphabet save an escape each time.
+        let value = |phrase : &[char], uses : usize| {
+            if phrase.len() == 1 {
+                uses
+            } else {
+                uses * (phrase.len() - 1)
+            }
+        };
+
+        let mut candidates = uses
+            .into_iter()
+            .filter(|(phrase, uses)| *uses > 0 && !(phrase.len() == 1 && alphabet.contains(&phrase[0])))
+            .map(|(phrase, uses)| (value(&phrase, uses), phrase))
+            .collect::<Vec<(usize, Vec<char>)>>();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.len().cmp(&b.1.len())).then(a.1.cmp(&b.1)));
+
+        let mut chosen = BTreeSet::new();
+        for (_, phrase) in candidates {
+            let missing = (1..=phrase.len())
+                .map(|length| &phrase[..length])
+                .filter(|prefix| !(prefix.len() == 1 && alphabet.contains(&prefix[0])))
+                .filter(|prefix| !chosen.contains(*prefix))
+                .map(|prefix| prefix.to_vec())
+                .collect::<Vec<Vec<char>>>();
+
+            if chosen.len() + missing.len() <= max_entries {
+                chosen.extend(missing);
+            }
+        }
+
+        PresetDictionary::new(mode, chosen)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Phrases in the order they get codewords
+    pub fn phrases(&self) -> &[Vec<char>] {
+        &self.phrases
+    }
+
+    pub fn len(&self) -> usize {
+        self.phrases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phrases.is_empty()
+    }
+
+    /// Checksum of the serialised dictionary, recorded in the container
+    /// header so the decoder can tell it was given the right one
+    pub fn id(&self) -> u32 {
+        crc32(&self.to_bytes())
+    }
+
+    /// Layout: magic (4), version (1), mode (1), phrase count (u32 LE), then
+    /// each phrase as its length in bytes (u32 LE) and the bytes `Mode::to_bytes` gives
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.mode.id());
+        bytes.extend((self.phrases.len() as u32).to_le_bytes());
+
+        for phrase in self.phrases.iter() {
+            let encoded = self.mode.to_bytes(phrase);
+            bytes.extend((encoded.len() as u32).to_le_bytes());
+            bytes.extend(encoded);
+        }
+        bytes
+    }
+
+    /// Reverses `to_bytes`
+    pub fn parse(input : &[u8]) -> Result<Self, Error> {
+        let truncated = Error::BadDictionary("truncated");
+
+        if !input.starts_with(&MAGIC) {
+            return Err(Error::BadDictionary("not a preset dictionary"));
+        }
+        if input.len() < 10 {
+            return Err(truncated);
+        }
+        if input[4] != VERSION {
+            return Err(Error::UnsupportedVersion(input[4]));
+        }
+
+        let mode = Mode::from_id(input[5]).ok_or(Error::BadDictionary("unknown mode"))?;
+        let count = u32::from_le_bytes(input[6..10].try_into().unwrap());
+
+        let mut rest = &input[10..];
+        let mut phrases = vec![];
+        for _ in 0..count {
+            if rest.len() < 4 {
+                return Err(truncated);
+            }
+            let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            rest = &rest[4..];
+
+            if rest.len() < length {
+                return Err(truncated);
+            }
+            phrases.push(mode.symbols(&rest[..length])?);
+            rest = &rest[length..];
+        }
+
+        PresetDictionary::new(mode, phrases)
+    }
+}
+
+
+#[cfg(test)]
+mod preset_test {
+    use std::sync::Arc;
+
+    use crate::container::*;
+    use crate::huffman::HuffmanStage;
+    use crate::lzw::*;
+    use crate::preset::*;
+
+    /// The folktale in a dozen words at a time, each one a short message
+    fn messages() -> Vec<Vec<u8>> {
+        let words = include_str!("../folktale.txt").split_whitespace().collect::<Vec<&str>>();
+        words.chunks(12).map(|chunk| chunk.join(" ").into_bytes()).collect()
+    }
+
+    #[test]
+    fn keeps_prefixes_and_skips_alphabet() {
+        let preset = PresetDictionary::new(Mode::Text, ["troll".chars().collect(), "«".chars().collect()]).unwrap();
+        let phrases = preset.phrases().iter().map(|p| p.iter().collect::<String>()).collect::<Vec<String>>();
+
+        assert_eq!(phrases, ["«", "tr", "tro", "trol", "troll"]);
+    }
+
+    #[test]
+    fn rejects_symbols_outside_mode() {
+        assert!(PresetDictionary::new(Mode::Bytes, [vec!['a', 'ø']]).is_ok());
+        assert!(matches!(PresetDictionary::new(Mode::Bytes, [vec!['€']]), Err(Error::UnknownSymbol('€'))));
+        assert!(matches!(PresetDictionary::new(Mode::Legacy, [vec!['a']]), Err(Error::BadDictionary(_))));
+    }
+
+    #[test]
+    fn serialised_round_trip() {
+        for mode in [Mode::Bytes, Mode::Text] {
+            let preset = PresetDictionary::train(mode, messages().iter().map(Vec::as_slice), 500).unwrap();
+            assert!(!preset.is_empty() && preset.len() <= 500);
+
+            let parsed = PresetDictionary::parse(&preset.to_bytes()).unwrap();
+            assert_eq!(parsed, preset);
+            assert_eq!(parsed.id(), preset.id());
+        }
+    }
+
+    #[test]
+    fn warms_up_short_messages() {
+        let messages = messages();
+        let (training, held_out) = messages.split_at(messages.len() / 2);
+        let preset = Arc::new(PresetDictionary::train(Mode::Text, training.iter().map(Vec::as_slice), 1000).unwrap());
+
+        for growth in [Growth::Classic, Growth::Lzap] {
+            let cold = LzwConfig { growth, ..LzwConfig::new(Mode::Text) };
+            let warm = LzwConfig { preset : Some(preset.clone()), ..cold.clone() };
+
+            let (mut cold_size, mut warm_size) = (0, 0);
+            for message in held_out {
+                cold_size += compress(message, &cold, HuffmanStage::Off).unwrap().len();
+
+                let compressed = compress(message, &warm, HuffmanStage::Off).unwrap();
+                assert_eq!(decompress(&compressed, false, Some(&preset)).unwrap(), *message);
+                warm_size += compressed.len();
+            }
+
+            assert!(warm_size < cold_size, "{growth:?}: preset {warm_size} vs none {cold_size}");
+        }
+    }
+
+    #[test]
+    fn decoder_checks_dictionary_id() {
+        let messages = messages();
+        let preset = Arc::new(PresetDictionary::train(Mode::Text, messages.iter().map(Vec::as_slice), 300).unwrap());
+        let other = Arc::new(PresetDictionary::train(Mode::Text, messages.iter().map(Vec::as_slice), 200).unwrap());
+
+        let config = LzwConfig { preset : Some(preset.clone()), ..LzwConfig::new(Mode::Text) };
+        let compressed = compress(&messages[0], &config, HuffmanStage::On).unwrap();
+
+        assert!(matches!(decompress(&compressed, false, None), Err(Error::MissingDictionary(id)) if id == preset.id()));
+        assert!(matches!(
+            decompress(&compressed, false, Some(&other)),
+            Err(Error::WrongDictionary { expected, found }) if expected == preset.id() && found == other.id()
+        ));
+        assert_eq!(decompress(&compressed, false, Some(&preset)).unwrap(), messages[0]);
+    }
+
+    #[test]
+    fn survives_reset_and_eviction() {
+        let text = include_str!("../folktale.txt").as_bytes();
+        let preset = Arc::new(PresetDictionary::train(Mode::Bytes, messages().iter().map(Vec::as_slice), 200).unwrap());
+
+        for full_policy in [FullPolicy::Clear, FullPolicy::Lru] {
+            for growth in [Growth::Classic, Growth::Lzmw] {
+                let config = LzwConfig {
+                    max_word_size : 9,
+                    full_policy,
+                    growth,
+                    preset : Some(preset.clone()),
+                    ..LzwConfig::new(Mode::Bytes)
+                };
+                let compressed = compress(text, &config, HuffmanStage::Off).unwrap();
+                assert_eq!(decompress(&compressed, false, Some(&preset)).unwrap(), text);
+            }
+        }
+    }
+}